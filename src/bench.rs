@@ -0,0 +1,154 @@
+// Benchmarks for chunk storage and generation, which are ignored tests so they can use the rest of the crate.
+// Run them with `cargo test --release bench -- --ignored --nocapture`; the timings don't mean anything in debug mode.
+
+use crate::common::*;
+use crate::generator::generate;
+use crate::terrain::*;
+use rayon::prelude::*;
+use std::time::Instant;
+
+const N: usize = 1_000_000;
+
+/// Generates the chunks around spawn, and compares them to storing every block flat, like chunks used to be
+#[test]
+#[ignore]
+fn chunks() {
+    let gen = Gen::new(0, GenConfig::default());
+
+    let timer = Instant::now();
+    let mut chunks = Vec::new();
+    for x in -4..4 {
+        for y in -3..3 {
+            for z in -4..4 {
+                chunks.push(generate(&gen, IVec3::new(x, y, z)));
+            }
+        }
+    }
+    // Chunks are compacted before they go in the world, so that's what we measure
+    for c in &mut chunks {
+        c.compact();
+    }
+    println!(
+        "Generated {} chunks in {:.2} ms/chunk",
+        chunks.len(),
+        timer.elapsed().as_secs_f64() * 1000.0 / chunks.len() as f64
+    );
+
+    let size = CHUNK_SIZE as usize;
+    let flat_size = size.pow(3) * std::mem::size_of::<Material>();
+    let sizes: Vec<usize> = chunks.iter().map(|c| c.memory()).collect();
+    let total: usize = sizes.iter().sum();
+    println!(
+        "Palette: {} KiB total, {} to {} bytes/chunk",
+        total / 1024,
+        sizes.iter().min().unwrap(),
+        sizes.iter().max().unwrap()
+    );
+    println!(
+        "Flat: {} KiB total, {} bytes/chunk",
+        flat_size * chunks.len() / 1024,
+        flat_size
+    );
+    println!(
+        "Palette chunks take up {:.1}% as much memory",
+        total as f64 * 100.0 / (flat_size * chunks.len()) as f64
+    );
+
+    let flat: Vec<Vec<Material>> = chunks
+        .iter()
+        .map(|c| {
+            (0..size)
+                .flat_map(|y| {
+                    (0..size)
+                        .flat_map(move |x| (0..size).map(move |z| c.block(UVec3::new(x, y, z))))
+                })
+                .collect()
+        })
+        .collect();
+
+    let mut rand = Rand::new(0, &[]);
+    let ops: Vec<(usize, UVec3)> = (0..N)
+        .map(|_| {
+            let c = rand.range(0, chunks.len() as i32) as usize;
+            let p = UVec3::new(
+                rand.range(0, size as i32) as usize,
+                rand.range(0, size as i32) as usize,
+                rand.range(0, size as i32) as usize,
+            );
+            (c, p)
+        })
+        .collect();
+
+    // We add up the ids so the reads can't be optimized out
+    let timer = Instant::now();
+    let sum: u64 = ops.iter().map(|(c, p)| chunks[*c].block(*p).0 as u64).sum();
+    println!(
+        "Palette block(): {:.1} ns ({})",
+        timer.elapsed().as_secs_f64() * 1e9 / N as f64,
+        sum
+    );
+    let timer = Instant::now();
+    let sum: u64 = ops
+        .iter()
+        .map(|(c, p)| flat[*c][p.y * size * size + p.x * size + p.z].0 as u64)
+        .sum();
+    println!(
+        "Flat block(): {:.1} ns ({})",
+        timer.elapsed().as_secs_f64() * 1e9 / N as f64,
+        sum
+    );
+
+    let mats = [
+        Material::AIR,
+        Material::STONE,
+        Material::DIRT,
+        Material::WOOD,
+        Material::COAL,
+    ];
+    let timer = Instant::now();
+    for (i, (c, p)) in ops.iter().enumerate() {
+        chunks[*c].set_block(*p, mats[i % mats.len()]);
+    }
+    println!(
+        "Palette set_block(): {:.1} ns",
+        timer.elapsed().as_secs_f64() * 1e9 / N as f64
+    );
+    let total: usize = chunks.iter().map(|c| c.memory()).sum();
+    println!(
+        "After setting blocks, palette chunks take up {:.1}% as much memory",
+        total as f64 * 100.0 / (flat_size * chunks.len()) as f64
+    );
+}
+
+/// Generates the same chunks with more and more threads, to see how chunk generation scales with cores
+#[test]
+#[ignore]
+fn gen() {
+    let gen = Gen::new(0, GenConfig::default());
+    let locs: Vec<IVec3> = (-6..6)
+        .flat_map(|x| (-3..3).flat_map(move |y| (-6..6).map(move |z| IVec3::new(x, y, z))))
+        .collect();
+
+    let cores = rayon::current_num_threads();
+    let mut threads: Vec<usize> = (0..).map(|i| 1 << i).take_while(|&n| n < cores).collect();
+    threads.push(cores);
+
+    let mut base = None;
+    for n in threads {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(n)
+            .build()
+            .unwrap();
+        let timer = Instant::now();
+        let chunks: Vec<Chunk> =
+            pool.install(|| locs.par_iter().map(|&p| generate(&gen, p)).collect());
+        let rate = chunks.len() as f64 / timer.elapsed().as_secs_f64();
+        let base = *base.get_or_insert(rate);
+        println!(
+            "{:>3} threads: {:.1} chunks/sec, {:.2}x one thread",
+            n,
+            rate,
+            rate / base
+        );
+    }
+}
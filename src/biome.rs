@@ -0,0 +1,138 @@
+use crate::common::*;
+use enum_iterator::IntoEnumIterator;
+use noise::{NoiseFn, Perlin, Seedable};
+use serde::{Deserialize, Serialize};
+
+/// How far apart biomes are in climate space before they stop blending together.
+/// Smaller means sharper borders.
+const BLEND: f64 = 0.08;
+
+#[derive(IntoEnumIterator, PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum Biome {
+    Plains,
+    Forest,
+    Desert,
+    Tundra,
+    Mountains,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct BiomeData {
+    /// Where the biome sits in `(temperature, humidity)` space; the biome closest to a column's climate wins
+    pub climate: (f64, f64),
+    /// The top block of each column
+    pub surface: Material,
+    /// The few blocks under the surface
+    pub subsurface: Material,
+    /// The height the hills are centered on, in meters
+    pub base: f32,
+    /// How tall the hills are, as a fraction of `GenConfig::height`
+    pub hills: f32,
+    /// How many trees there are compared to a forest
+    pub trees: f64,
+}
+
+impl Biome {
+    pub fn data(self) -> BiomeData {
+        match self {
+            Biome::Plains => BiomeData {
+                climate: (0.0, 0.0),
+                surface: Material::GRASS,
+                subsurface: Material::DIRT,
+                base: 3.0,
+                hills: 0.6,
+                trees: 0.15,
+            },
+            Biome::Forest => BiomeData {
+                climate: (0.0, 0.4),
+                surface: Material::GRASS,
+                subsurface: Material::DIRT,
+                base: 3.0,
+                hills: 1.0,
+                trees: 1.0,
+            },
+            Biome::Desert => BiomeData {
+                climate: (0.4, -0.3),
+                surface: Material::SAND,
+                subsurface: Material::SAND,
+                base: 4.0,
+                hills: 0.5,
+                trees: 0.0,
+            },
+            Biome::Tundra => BiomeData {
+                climate: (-0.4, 0.0),
+                surface: Material::SNOW,
+                subsurface: Material::DIRT,
+                base: 3.0,
+                hills: 0.8,
+                trees: 0.1,
+            },
+            Biome::Mountains => BiomeData {
+                climate: (-0.1, -0.4),
+                surface: Material::STONE,
+                subsurface: Material::STONE,
+                base: 20.0,
+                hills: 2.5,
+                trees: 0.05,
+            },
+        }
+    }
+}
+
+/// Decides which biome each column is in, from temperature and humidity noise
+pub struct BiomeMap {
+    temperature: Perlin,
+    humidity: Perlin,
+    /// The frequency of the climate noise, per meter
+    scale: f64,
+}
+
+impl BiomeMap {
+    pub fn new(seed: u32, scale: f64) -> Self {
+        // Offset the seeds so they don't line up with the heightmap's octaves
+        BiomeMap {
+            temperature: Perlin::new().set_seed(seed.wrapping_add(1000)),
+            humidity: Perlin::new().set_seed(seed.wrapping_add(2000)),
+            scale,
+        }
+    }
+
+    fn climate(&self, x: f64, z: f64) -> (f64, f64) {
+        let p = [x * self.scale, z * self.scale];
+        (self.temperature.get(p), self.humidity.get(p))
+    }
+
+    /// How much each biome contributes to the column at `(x, z)`, in `Biome::into_enum_iter()` order.
+    /// They add up to 1; away from borders, one biome is close to 1 and the rest are close to 0.
+    pub fn weights(&self, x: f64, z: f64) -> Vec<f32> {
+        let (t, h) = self.climate(x, z);
+        let d2: Vec<f64> = Biome::into_enum_iter()
+            .map(|b| {
+                let (bt, bh) = b.data().climate;
+                (t - bt).powi(2) + (h - bh).powi(2)
+            })
+            .collect();
+        // Subtract the closest so the exponent can't underflow to zero for everything
+        let min = d2.iter().cloned().fold(f64::INFINITY, f64::min);
+        let w: Vec<f64> = d2
+            .iter()
+            .map(|d| (-(d - min) / (BLEND * BLEND)).exp())
+            .collect();
+        let total: f64 = w.iter().sum();
+        w.into_iter().map(|x| (x / total) as f32).collect()
+    }
+
+    /// The biome that contributes the most to the column at `(x, z)`
+    pub fn biome(&self, x: f64, z: f64) -> Biome {
+        let (t, h) = self.climate(x, z);
+        Biome::into_enum_iter()
+            .min_by(|a, b| {
+                let (at, ah) = a.data().climate;
+                let (bt, bh) = b.data().climate;
+                let da = (t - at).powi(2) + (h - ah).powi(2);
+                let db = (t - bt).powi(2) + (h - bh).powi(2);
+                da.partial_cmp(&db).unwrap_or(std::cmp::Ordering::Equal)
+            })
+            .unwrap()
+    }
+}
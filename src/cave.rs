@@ -0,0 +1,97 @@
+use noise::{NoiseFn, Perlin, Seedable};
+
+/// The most the overhang noise can move the surface up or down, in meters
+pub const OVERHANG: f32 = 8.0;
+
+/// Caverns only show up this far below sea level
+const CAVERN_DEPTH: i32 = 16;
+/// How far down from the surface ravines go
+const RAVINE_DEPTH: f32 = 40.0;
+
+/// The 3D parts of terrain generation: overhangs and arches along the surface,
+/// and worm caves, caverns and ravines carved out underneath.
+/// Like the rest of `Gen`, everything here depends only on the seed.
+pub struct Caves {
+    overhang: Perlin,
+    overhang_mask: Perlin,
+    worm_a: Perlin,
+    worm_b: Perlin,
+    cavern: Perlin,
+    ravine: Perlin,
+    ravine_mask: Perlin,
+}
+
+impl Caves {
+    pub fn new(seed: u32) -> Self {
+        let perlin = |offset| Perlin::new().set_seed(seed.wrapping_add(offset));
+        Caves {
+            overhang: perlin(3000),
+            overhang_mask: perlin(3001),
+            worm_a: perlin(3002),
+            worm_b: perlin(3003),
+            cavern: perlin(3004),
+            ravine: perlin(3005),
+            ravine_mask: perlin(3006),
+        }
+    }
+
+    /// Whether the block at `(x, y, z)` is part of the terrain, before carving, given the heightmap's `height` there.
+    /// Without overhangs, that's everything up to and including `height.ceil()`.
+    pub fn solid(&self, x: f64, y: i32, z: f64, height: f32) -> bool {
+        let y_f = y as f32;
+        // Out of reach of the overhang noise, so it's just the heightmap
+        if y_f <= height - OVERHANG {
+            return true;
+        } else if y_f >= height + OVERHANG + 1.0 {
+            return false;
+        }
+
+        // Overhangs only show up in patches, so most of the terrain stays walkable
+        let amp = OVERHANG
+            * (self.overhang_mask.get([x * 0.004, z * 0.004]) as f32 * 2.0).clamp(0.0, 1.0);
+        if amp == 0.0 {
+            return y <= height.ceil() as i32;
+        }
+        let n = self.overhang.get([x * 0.03, y as f64 * 0.05, z * 0.03]) as f32;
+        y <= (height + amp * n).ceil() as i32
+    }
+
+    /// Whether a cave, cavern or ravine carves out the block at `(x, y, z)`, given the heightmap's `height` there
+    pub fn carved(&self, x: f64, y: i32, z: f64, height: f32) -> bool {
+        // Don't poke holes in the sea floor
+        if height < 2.0 && y as f32 > height - 6.0 {
+            return false;
+        }
+
+        // Worm caves are where two noise functions are both close to zero, which makes long winding tubes.
+        // The y axis is squashed so they're mostly horizontal.
+        let p = [x * 0.015, y as f64 * 0.025, z * 0.015];
+        if self.worm_a.get(p).abs() < 0.05 && self.worm_b.get(p).abs() < 0.05 {
+            return true;
+        }
+
+        // Caverns get bigger the deeper you go
+        if y < -CAVERN_DEPTH {
+            let depth = (-CAVERN_DEPTH - y) as f64;
+            let threshold = 0.7 - (depth / 200.0).min(0.2);
+            if self.cavern.get([x * 0.008, y as f64 * 0.012, z * 0.008]) > threshold {
+                return true;
+            }
+        }
+
+        // Ravines are narrow cracks down from the surface, which taper off at the bottom and at the ends
+        let depth = height - y as f32;
+        if depth > -OVERHANG && depth < RAVINE_DEPTH {
+            let mask = ((self.ravine_mask.get([x * 0.002, z * 0.002]) as f32 - 0.35) / 0.15)
+                .clamp(0.0, 1.0);
+            if mask > 0.0 {
+                let width = 0.03 * mask * (1.0 - depth.max(0.0) / RAVINE_DEPTH);
+                if (self.ravine.get([x * 0.004, z * 0.004]) as f32).abs() < width {
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+}
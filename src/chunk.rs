@@ -1,101 +1,400 @@
 use crate::common::*;
 use serde::{Deserialize, Serialize};
 
+/// The blocks in a chunk, as a palette of the materials in it and a bit-packed index into the palette for each block.
+/// Blocks are in the order `y * CHUNK_SIZE * CHUNK_SIZE + x * CHUNK_SIZE + z` for cache friendliness.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum Chunk {
-    /// Indexed by `blocks[y * CHUNK_SIZE * CHUNK_SIZE + x * CHUNK_SIZE + z]` for cache friendliness
+#[serde(try_from = "SavedChunk", into = "SavedChunk")]
+pub struct Chunk {
+    palette: Vec<Material>,
+    /// How many blocks use each palette entry, so we can reuse the ones nothing uses anymore
+    counts: Vec<u32>,
+    /// The number of bits for each block's palette index, which is always a power of two so none straddle two words.
+    /// It's 0 when there's only one material, and then `data` is empty.
+    bits: u32,
+    data: Vec<u64>,
+    /// The pipeline works this out for new chunks, but it isn't saved or sent,
+    /// since the client works it out itself; see `light.rs`
+    light: LightMap,
+}
+
+/// The light level of each block in a chunk, with sky light in the high four bits and light from blocks in the low four.
+/// It doesn't store each block if they're all the same, like in the open sky or deep underground.
+#[derive(Debug, Clone, Default)]
+struct LightMap {
+    fill: u8,
+    data: Vec<u8>,
+}
+
+impl LightMap {
+    fn new(data: Vec<u8>) -> Self {
+        if data.iter().all(|&l| l == data[0]) {
+            LightMap {
+                fill: data[0],
+                data: Vec::new(),
+            }
+        } else {
+            LightMap { fill: 0, data }
+        }
+    }
+
+    fn get(&self, i: usize) -> u8 {
+        if self.data.is_empty() {
+            self.fill
+        } else {
+            self.data[i]
+        }
+    }
+
+    fn set(&mut self, i: usize, l: u8) {
+        if self.data.is_empty() {
+            if l == self.fill {
+                return;
+            }
+            self.data = vec![self.fill; VOLUME];
+        }
+        self.data[i] = l;
+    }
+}
+
+/// How chunks are saved and sent.
+/// The first two are how chunks used to be stored, so we can still load them.
+#[derive(Clone, Serialize, Deserialize)]
+enum SavedChunk {
     Flat(Vec<Material>),
-    /// Same index. (length, mat)
+    /// (length, mat)
     Runs(Vec<(u16, Material)>),
+    Palette {
+        palette: Vec<Material>,
+        bits: u32,
+        data: Vec<u64>,
+    },
+}
+
+impl std::convert::TryFrom<SavedChunk> for Chunk {
+    type Error = String;
+
+    fn try_from(saved: SavedChunk) -> Result<Chunk, String> {
+        match saved {
+            SavedChunk::Flat(blocks) => Chunk::from_blocks(blocks.into_iter()),
+            SavedChunk::Runs(runs) => Chunk::from_blocks(
+                runs.into_iter()
+                    .flat_map(|(len, b)| (0..len).map(move |_| b)),
+            ),
+            SavedChunk::Palette {
+                palette,
+                bits,
+                data,
+            } => {
+                if bits > 16
+                    || bits_for(1 << bits) != bits
+                    || data.len() != VOLUME * bits as usize / 64
+                {
+                    return Err(format!("bad chunk: {} bits and {} words", bits, data.len()));
+                }
+                let mut chunk = Chunk {
+                    counts: vec![0; palette.len()],
+                    palette,
+                    bits,
+                    data,
+                    light: LightMap::default(),
+                };
+                for i in 0..VOLUME {
+                    let p = chunk.get(i);
+                    if p >= chunk.palette.len() {
+                        return Err(format!("bad chunk: palette index {} out of range", p));
+                    }
+                    chunk.counts[p] += 1;
+                }
+                Ok(chunk)
+            }
+        }
+    }
+}
+
+impl From<Chunk> for SavedChunk {
+    fn from(chunk: Chunk) -> SavedChunk {
+        SavedChunk::Palette {
+            palette: chunk.palette,
+            bits: chunk.bits,
+            data: chunk.data,
+        }
+    }
 }
 
 const CHUNK_U: usize = CHUNK_SIZE as usize;
+const VOLUME: usize = CHUNK_U * CHUNK_U * CHUNK_U;
+
+fn index(idx: UVec3) -> usize {
+    idx.y * CHUNK_U * CHUNK_U + idx.x * CHUNK_U + idx.z
+}
+
+/// Calls `f` with each block in the box from `min` to `max`, inclusive, in the same order as the chunk
+fn each(min: UVec3, max: UVec3, mut f: impl FnMut(UVec3)) {
+    for y in min.y..=max.y {
+        for x in min.x..=max.x {
+            for z in min.z..=max.z {
+                f(UVec3::new(x, y, z));
+            }
+        }
+    }
+}
+
+/// The fewest bits per block that can index `n` palette entries
+fn bits_for(n: usize) -> u32 {
+    match n {
+        0..=1 => 0,
+        2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        17..=256 => 8,
+        _ => 16,
+    }
+}
 
 impl Chunk {
     pub fn empty() -> Self {
-        Chunk::Runs(vec![((CHUNK_U * CHUNK_U * CHUNK_U) as u16, Material::Air)])
+        Chunk::filled(Material::AIR)
+    }
+
+    /// A chunk that's all `m`
+    fn filled(m: Material) -> Self {
+        Chunk {
+            palette: vec![m],
+            counts: vec![VOLUME as u32],
+            bits: 0,
+            data: Vec::new(),
+            light: LightMap::default(),
+        }
     }
+
     pub fn full(f: &impl Fn(UVec3) -> Material) -> Self {
-        let mut runs: Vec<(u16, Material)> = Vec::new();
-        let mut blocks: Vec<Material> = Vec::new();
-        for y in 0..CHUNK_U {
-            for x in 0..CHUNK_U {
-                for z in 0..CHUNK_U {
-                    let b = f(UVec3::new(x, y, z));
-                    if runs.last().map_or(false, |x| x.1 == b) {
-                        runs.last_mut().unwrap().0 += 1;
-                    } else {
-                        runs.push((1, b));
-                    }
-                    blocks.push(b);
-                }
-            }
+        Chunk::from_blocks((0..CHUNK_U).flat_map(|y| {
+            (0..CHUNK_U).flat_map(move |x| (0..CHUNK_U).map(move |z| f(UVec3::new(x, y, z))))
+        }))
+        .unwrap()
+    }
+
+    /// `blocks` has to be in the same order as the chunk
+    fn from_blocks(blocks: impl Iterator<Item = Material>) -> Result<Self, String> {
+        let mut palette = Vec::new();
+        let mut counts = Vec::new();
+        let indices: Vec<usize> = blocks
+            .map(|b| {
+                let p = palette.iter().position(|&x| x == b).unwrap_or_else(|| {
+                    palette.push(b);
+                    counts.push(0);
+                    palette.len() - 1
+                });
+                counts[p] += 1;
+                p
+            })
+            .collect();
+        if indices.len() != VOLUME {
+            return Err(format!("bad chunk: {} blocks", indices.len()));
         }
-        if runs.len() <= 16 {
-            Chunk::Runs(runs)
-        } else {
-            Chunk::Flat(blocks)
+
+        let mut chunk = Chunk {
+            bits: bits_for(palette.len()),
+            palette,
+            counts,
+            data: Vec::new(),
+            light: LightMap::default(),
+        };
+        chunk.data = vec![0; VOLUME * chunk.bits as usize / 64];
+        for (i, p) in indices.into_iter().enumerate() {
+            chunk.put(i, p);
         }
+        Ok(chunk)
     }
 
-    pub fn block(&self, idx: UVec3) -> Material {
-        match self {
-            Chunk::Flat(ref blocks) => blocks[idx.y * CHUNK_U * CHUNK_U + idx.x * CHUNK_U + idx.z],
-            Chunk::Runs(ref runs) => {
-                let mut ret = Material::Air;
-                let i = idx.y * CHUNK_U * CHUNK_U + idx.x * CHUNK_U + idx.z;
-                let mut c = 0;
-                for (l, b) in runs {
-                    c += l;
-                    if i < c.into() {
-                        ret = *b;
-                        break;
-                    }
+    /// The palette index of block `i`
+    fn get(&self, i: usize) -> usize {
+        if self.bits == 0 {
+            return 0;
+        }
+        let per = 64 / self.bits as usize;
+        let word = self.data[i / per];
+        ((word >> ((i % per) * self.bits as usize)) & ((1 << self.bits) - 1)) as usize
+    }
+
+    fn put(&mut self, i: usize, p: usize) {
+        if self.bits == 0 {
+            return;
+        }
+        let per = 64 / self.bits as usize;
+        let shift = (i % per) * self.bits as usize;
+        let mask = ((1u64 << self.bits) - 1) << shift;
+        let word = &mut self.data[i / per];
+        *word = (*word & !mask) | ((p as u64) << shift);
+    }
+
+    /// Switches to `bits` bits per block, which has to be enough for every palette index in use
+    fn repack(&mut self, bits: u32) {
+        let indices: Vec<usize> = (0..VOLUME).map(|i| self.get(i)).collect();
+        self.bits = bits;
+        self.data = vec![0; VOLUME * bits as usize / 64];
+        for (i, p) in indices.into_iter().enumerate() {
+            self.put(i, p);
+        }
+    }
+
+    /// Drops palette entries nothing uses anymore and uses as few bits as possible, which is the smallest a chunk can be.
+    /// Editing a chunk only ever grows it, so this is done before saving it.
+    pub fn compact(&mut self) {
+        if self.counts.iter().all(|&c| c > 0) && bits_for(self.palette.len()) == self.bits {
+            return;
+        }
+
+        let mut palette = Vec::new();
+        let mut counts = Vec::new();
+        let remap: Vec<usize> = self
+            .palette
+            .iter()
+            .zip(&self.counts)
+            .map(|(&m, &c)| {
+                if c > 0 {
+                    palette.push(m);
+                    counts.push(c);
+                    palette.len() - 1
+                } else {
+                    // Nothing points here
+                    0
                 }
-                ret
-            }
+            })
+            .collect();
+        let indices: Vec<usize> = (0..VOLUME).map(|i| remap[self.get(i)]).collect();
+
+        self.bits = bits_for(palette.len());
+        self.palette = palette;
+        self.counts = counts;
+        self.data = vec![0; VOLUME * self.bits as usize / 64];
+        for (i, p) in indices.into_iter().enumerate() {
+            self.put(i, p);
         }
     }
-    pub fn set_block(&mut self, idx: UVec3, new: Material) {
-        match self {
-            Chunk::Flat(ref mut blocks) => {
-                blocks[idx.y * CHUNK_U * CHUNK_U + idx.x * CHUNK_U + idx.z] = new;
-            }
-            Chunk::Runs(ref mut runs) => {
-                let i = idx.y * CHUNK_U * CHUNK_U + idx.x * CHUNK_U + idx.z;
-                let mut c = 0;
-                for j in 0..runs.len() {
-                    let (l, b) = runs[j];
-                    c += l;
-                    if i < c.into() {
-                        let start = c - l;
-                        let new_len = i as u16 - start;
-                        runs[j].0 = new_len;
-                        runs.insert(j + 1, (1, new));
-                        let after = c - (i + 1) as u16;
-                        if after != 0 {
-                            runs.insert(j + 2, (after, b));
-                        }
-                        break;
-                    }
+
+    pub fn block(&self, idx: UVec3) -> Material {
+        self.palette[self.get(index(idx))]
+    }
+
+    /// The palette index of `m`, adding it to the palette if it isn't there yet
+    fn entry(&mut self, m: Material) -> usize {
+        let p = match self.palette.iter().position(|&x| x == m) {
+            Some(p) => p,
+            // Reuse an entry nothing uses anymore if there is one
+            None => match self.counts.iter().position(|&c| c == 0) {
+                Some(p) => {
+                    self.palette[p] = m;
+                    p
                 }
-                if runs.len() > 16 {
-                    // RLE is no longer suitable for this chunk, switch to flat encoding
-                    self.flatten();
+                None => {
+                    self.palette.push(m);
+                    self.counts.push(0);
+                    self.palette.len() - 1
                 }
-            }
+            },
+        };
+        if p >= 1 << self.bits {
+            self.repack(bits_for(self.palette.len()));
         }
+        p
     }
 
-    fn flatten(&mut self) {
-        if let Chunk::Runs(runs) = self {
-            let mut blocks = Vec::new();
-            // We can do this because the flat array is in the same order as the runs
-            for (len, b) in runs {
-                blocks.extend((0..*len).map(|_| *b));
-            }
-            *self = Chunk::Flat(blocks);
+    /// Points block `i` at palette entry `p`, and returns whether it changed
+    fn set_entry(&mut self, i: usize, p: usize) -> bool {
+        let old = self.get(i);
+        if old == p {
+            return false;
         }
+        self.counts[old] -= 1;
+        self.counts[p] += 1;
+        self.put(i, p);
+        true
+    }
+
+    pub fn set_block(&mut self, idx: UVec3, new: Material) {
+        let i = index(idx);
+        if self.palette[self.get(i)] == new {
+            return;
+        }
+        let p = self.entry(new);
+        self.set_entry(i, p);
+    }
+
+    /// The light at `idx`: sky light in the high four bits, and block light in the low four
+    pub fn light(&self, idx: UVec3) -> u8 {
+        self.light.get(index(idx))
+    }
+
+    pub fn set_light(&mut self, idx: UVec3, l: u8) {
+        self.light.set(index(idx), l);
+    }
+
+    /// Sets the light of every block at once, in the same order as the chunk
+    pub fn set_lights(&mut self, lights: Vec<u8>) {
+        self.light = LightMap::new(lights);
+    }
+
+    /// If every block in the chunk is the same, that material
+    pub fn uniform(&self) -> Option<Material> {
+        let mut used = self
+            .palette
+            .iter()
+            .zip(&self.counts)
+            .filter(|(_, &c)| c > 0);
+        match (used.next(), used.next()) {
+            (Some((&m, _)), None) => Some(m),
+            _ => None,
+        }
+    }
+
+    /// About how many bytes the chunk takes up in memory, for `bench`
+    #[cfg(test)]
+    pub fn memory(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.palette.capacity() * std::mem::size_of::<Material>()
+            + self.counts.capacity() * std::mem::size_of::<u32>()
+            + self.data.capacity() * std::mem::size_of::<u64>()
+            + self.light.data.capacity()
+    }
+
+    /// The light on each face from `cull_faces`, indexed the same way.
+    /// Light doesn't go into opaque blocks, so this uses whichever side of the face is brighter.
+    pub fn face_lights(&self, axis: usize, neighbors: (&Self, &Self)) -> Vec<Vec<Vec<u8>>> {
+        let u = (axis + 1) % 3;
+        let v = (axis + 2) % 3;
+
+        (0..=CHUNK_U)
+            .map(|d_i| {
+                (0..CHUNK_U)
+                    .map(|u_i| {
+                        (0..CHUNK_U)
+                            .map(|v_i| {
+                                let mut idx = UVec3::zeros();
+                                idx[u] = u_i;
+                                idx[v] = v_i;
+                                let back = if d_i == 0 {
+                                    idx[axis] = CHUNK_U - 1;
+                                    neighbors.0.light(idx)
+                                } else {
+                                    idx[axis] = d_i - 1;
+                                    self.light(idx)
+                                };
+                                let front = if d_i == CHUNK_U {
+                                    idx[axis] = 0;
+                                    neighbors.1.light(idx)
+                                } else {
+                                    idx[axis] = d_i;
+                                    self.light(idx)
+                                };
+                                (back & 0xF0).max(front & 0xF0) | (back & 0x0F).max(front & 0x0F)
+                            })
+                            .collect()
+                    })
+                    .collect()
+            })
+            .collect()
     }
 
     /// Indexed by `faces[axis][u][v]` where `u = (axis + 1) % 3; v = (axis + 2) % 3;`
@@ -104,19 +403,16 @@ impl Chunk {
         axis: usize,
         neighbors: (&Self, &Self),
         phase2: bool,
+        registry: &Registry,
     ) -> Vec<Vec<Vec<Material>>> {
         // Special case
-        if let Chunk::Runs(runs) = self {
-            if runs.len() == 1 {
-                let r = runs.first().unwrap();
-                if r.1 == Material::Air {
-                    return Vec::new();
-                }
-            }
+        if self.uniform() == Some(Material::AIR) {
+            return Vec::new();
         }
 
         let u = (axis + 1) % 3;
         let v = (axis + 2) % 3;
+        let is_phase2 = |m: Material| registry.get(m).phase2;
 
         let mut last: Vec<Vec<Material>> = {
             let f = neighbors.0;
@@ -129,10 +425,10 @@ impl Chunk {
                             idx[u] = u_i;
                             idx[v] = v_i;
                             let b = f.block(idx);
-                            if !b.phase2() || phase2 {
+                            if !is_phase2(b) || phase2 {
                                 b
                             } else {
-                                Material::Air
+                                Material::AIR
                             }
                         })
                         .collect()
@@ -171,29 +467,29 @@ impl Chunk {
                         let b = self.block(idx);
                         let l = last[u_i][v_i];
                         culled[d_i][u_i].push(
-                            if (l == Material::Air || (!l.phase2() && phase2))
-                                && b.phase2() == phase2
+                            if (l == Material::AIR || (!is_phase2(l) && phase2))
+                                && is_phase2(b) == phase2
                             {
                                 b
-                            } else if b == Material::Air || (b.phase2() && !phase2) {
+                            } else if b == Material::AIR || (is_phase2(b) && !phase2) {
                                 l
                             } else {
-                                Material::Air
+                                Material::AIR
                             },
                         );
-                        last[u_i as usize][v_i as usize] = if b.phase2() == phase2 {
+                        last[u_i][v_i] = if is_phase2(b) == phase2 {
                             b
                         } else {
-                            Material::Air
+                            Material::AIR
                         };
                     } else {
                         // The last edge
                         let l = last[u_i][v_i];
                         let b = end[u_i][v_i];
-                        culled[d_i][u_i].push(if b == Material::Air || (b.phase2() && !phase2) {
+                        culled[d_i][u_i].push(if b == Material::AIR || (is_phase2(b) && !phase2) {
                             l
                         } else {
-                            Material::Air
+                            Material::AIR
                         });
                     }
                 }
@@ -203,3 +499,353 @@ impl Chunk {
         culled
     }
 }
+
+// Bulk editing, for world editing tools and the like.
+// Boxes are in chunk coordinates and include both corners.
+impl Chunk {
+    /// Sets every block from `min` to `max` to `m`, and returns whether anything changed
+    pub fn fill_box(&mut self, min: UVec3, max: UVec3, m: Material) -> bool {
+        if min == UVec3::zeros() && max == UVec3::repeat(CHUNK_U - 1) {
+            if self.uniform() == Some(m) {
+                return false;
+            }
+            self.palette = vec![m];
+            self.counts = vec![VOLUME as u32];
+            self.bits = 0;
+            self.data = Vec::new();
+            return true;
+        }
+        let p = self.entry(m);
+        let mut changed = false;
+        each(min, max, |b| changed |= self.set_entry(index(b), p));
+        changed
+    }
+
+    /// Sets every block with its center within `radius` of `center` to `m`, and returns whether anything changed.
+    /// `center` is relative to the corner of the chunk, so the sphere can be centered outside of it.
+    pub fn fill_sphere(&mut self, center: Vec3, radius: f32, m: Material) -> bool {
+        let min = (center - Vec3::repeat(radius)).map(|x| x.floor().max(0.0) as usize);
+        let max = (center + Vec3::repeat(radius)).map(|x| x.ceil().min(CHUNK_SIZE - 1.0));
+        if max.iter().any(|&x| x < 0.0) {
+            return false;
+        }
+        let max = max.map(|x| x as usize);
+
+        let p = self.entry(m);
+        let mut changed = false;
+        each(min, max, |b| {
+            if (b.map(|x| x as f32 + 0.5) - center).norm() <= radius {
+                changed |= self.set_entry(index(b), p);
+            }
+        });
+        changed
+    }
+
+    /// Turns every `from` block between `min` and `max` into `to`, and returns whether anything changed
+    pub fn replace(&mut self, min: UVec3, max: UVec3, from: Material, to: Material) -> bool {
+        let old = match self.palette.iter().position(|&x| x == from) {
+            Some(p) if self.counts[p] > 0 && from != to => p,
+            _ => return false,
+        };
+        // For the whole chunk, we can usually just change the palette
+        if min == UVec3::zeros() && max == UVec3::repeat(CHUNK_U - 1) && !self.palette.contains(&to)
+        {
+            self.palette[old] = to;
+            return true;
+        }
+
+        let new = self.entry(to);
+        let mut changed = false;
+        each(min, max, |b| {
+            let i = index(b);
+            if self.get(i) == old {
+                changed |= self.set_entry(i, new);
+            }
+        });
+        changed
+    }
+
+    /// Copies the blocks from `min` to `max`
+    pub fn copy_region(&self, min: UVec3, max: UVec3) -> Schematic {
+        let mut s = Schematic::new(max + UVec3::repeat(1) - min);
+        each(min, max, |b| s.set_block(b - min, self.block(b)));
+        s
+    }
+
+    /// Pastes `s` with its corner at `at`, which is relative to the corner of the chunk.
+    /// Only the part of `s` inside the chunk is pasted, so it can hang over the edges.
+    /// If `skip_air` is true, air in `s` doesn't replace anything.
+    /// Returns whether anything changed.
+    pub fn paste_region(&mut self, at: IVec3, s: &Schematic, skip_air: bool) -> bool {
+        let min = at.map(|x| x.max(0));
+        let end = (at + s.size().map(|x| x as i32)).map(|x| x.min(CHUNK_SIZE as i32));
+        if (0..3).any(|a| min[a] >= end[a]) {
+            return false;
+        }
+
+        let mut changed = false;
+        each(min.map(|x| x as usize), end.map(|x| x as usize - 1), |b| {
+            let m = s.block((b.map(|x| x as i32) - at).map(|x| x as usize));
+            if !(skip_air && m == Material::AIR) {
+                let p = self.entry(m);
+                changed |= self.set_entry(index(b), p);
+            }
+        });
+        changed
+    }
+}
+
+/// A box of blocks copied out of a chunk or the world, which can be pasted somewhere else
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Schematic {
+    size: UVec3,
+    /// In the same order as in chunks
+    blocks: Vec<Material>,
+}
+
+impl Schematic {
+    /// A schematic that's all air
+    pub fn new(size: UVec3) -> Self {
+        Schematic {
+            size,
+            blocks: vec![Material::AIR; size.x * size.y * size.z],
+        }
+    }
+
+    pub fn size(&self) -> UVec3 {
+        self.size
+    }
+
+    fn index(&self, idx: UVec3) -> usize {
+        (idx.y * self.size.x + idx.x) * self.size.z + idx.z
+    }
+
+    pub fn block(&self, idx: UVec3) -> Material {
+        self.blocks[self.index(idx)]
+    }
+
+    pub fn set_block(&mut self, idx: UVec3, m: Material) {
+        let i = self.index(idx);
+        self.blocks[i] = m;
+    }
+
+    /// Copies all of `other` into this one, with its corner at `at`
+    pub fn blit(&mut self, at: UVec3, other: &Schematic) {
+        if other.blocks.is_empty() {
+            return;
+        }
+        each(UVec3::zeros(), other.size - UVec3::repeat(1), |b| {
+            self.set_block(at + b, other.block(b))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    /// Every block, in the same order as the chunk
+    fn snapshot(chunk: &Chunk) -> Vec<Material> {
+        let mut blocks = Vec::new();
+        each(UVec3::zeros(), UVec3::repeat(CHUNK_U - 1), |b| {
+            blocks.push(chunk.block(b))
+        });
+        blocks
+    }
+
+    fn random_block(rand: &mut Rand) -> UVec3 {
+        UVec3::from_fn(|_, _| rand.range(0, CHUNK_U as i32) as usize)
+    }
+
+    /// A random box, which is sometimes the whole chunk
+    fn random_box(rand: &mut Rand) -> (UVec3, UVec3) {
+        if rand.next() < 0.2 {
+            return (UVec3::zeros(), UVec3::repeat(CHUNK_U - 1));
+        }
+        let (a, b) = (random_block(rand), random_block(rand));
+        (a.zip_map(&b, usize::min), a.zip_map(&b, usize::max))
+    }
+
+    /// A chunk after a random sequence of edits with up to `materials` different materials
+    fn random_chunk(seed: u32, materials: i32) -> Chunk {
+        let mut rand = Rand::new(seed, &[materials as i64]);
+        let mut chunk = Chunk::empty();
+        for _ in 0..rand.range(1, 12) {
+            let m = Material(rand.range(0, materials) as u16);
+            match rand.range(0, 4) {
+                0 => {
+                    for _ in 0..rand.range(1, 100) {
+                        let m = Material(rand.range(0, materials) as u16);
+                        chunk.set_block(random_block(&mut rand), m);
+                    }
+                }
+                1 => {
+                    let (min, max) = random_box(&mut rand);
+                    chunk.fill_box(min, max, m);
+                }
+                2 => {
+                    let (min, max) = random_box(&mut rand);
+                    let from = Material(rand.range(0, materials) as u16);
+                    chunk.replace(min, max, from, m);
+                }
+                _ => {
+                    let center = random_block(&mut rand).map(|x| x as f32);
+                    chunk.fill_sphere(center, rand.range(1, 20) as f32, m);
+                }
+            }
+        }
+        chunk
+    }
+
+    /// Checks that the palette, counts and bits all agree with the blocks in `chunk`
+    fn check(chunk: &Chunk, blocks: &[Material]) {
+        assert_eq!(snapshot(chunk), blocks);
+        assert_eq!(chunk.data.len(), VOLUME * chunk.bits as usize / 64);
+        let mut counts = vec![0; chunk.palette.len()];
+        for &b in blocks {
+            counts[chunk.palette.iter().position(|&m| m == b).unwrap()] += 1;
+        }
+        assert_eq!(counts, chunk.counts);
+    }
+
+    #[test]
+    fn compact() {
+        for seed in 0..25 {
+            for &materials in &[2, 5, 20] {
+                let mut chunk = random_chunk(seed, materials);
+                let blocks = snapshot(&chunk);
+                check(&chunk, &blocks);
+
+                chunk.compact();
+                check(&chunk, &blocks);
+                assert!(chunk.counts.iter().all(|&c| c > 0));
+                assert_eq!(chunk.bits, bits_for(chunk.palette.len()));
+                assert_eq!(chunk.uniform().is_some(), chunk.palette.len() == 1);
+
+                // And it keeps working after that
+                chunk.set_block(UVec3::new(1, 2, 3), Material(materials as u16));
+                let mut blocks = blocks;
+                blocks[index(UVec3::new(1, 2, 3))] = Material(materials as u16);
+                check(&chunk, &blocks);
+            }
+        }
+    }
+
+    #[test]
+    fn saved() {
+        for seed in 0..10 {
+            for &materials in &[1, 3, 20] {
+                let chunk = random_chunk(seed, materials);
+                let blocks = snapshot(&chunk);
+
+                let saved = Chunk::try_from(SavedChunk::from(chunk.clone())).unwrap();
+                check(&saved, &blocks);
+
+                let bytes = bincode::serialize(&chunk).unwrap();
+                let sent: Chunk = bincode::deserialize(&bytes).unwrap();
+                check(&sent, &blocks);
+
+                // The old formats
+                let flat = Chunk::try_from(SavedChunk::Flat(blocks.clone())).unwrap();
+                check(&flat, &blocks);
+                let mut runs: Vec<(u16, Material)> = Vec::new();
+                for &b in &blocks {
+                    match runs.last_mut() {
+                        Some((len, m)) if *m == b => *len += 1,
+                        _ => runs.push((1, b)),
+                    }
+                }
+                let runs = Chunk::try_from(SavedChunk::Runs(runs)).unwrap();
+                check(&runs, &blocks);
+            }
+        }
+    }
+
+    #[test]
+    fn bad_saves() {
+        assert!(Chunk::try_from(SavedChunk::Flat(vec![Material::STONE; 10])).is_err());
+        assert!(Chunk::try_from(SavedChunk::Palette {
+            palette: vec![Material::STONE],
+            bits: 3,
+            data: vec![0; VOLUME * 3 / 64],
+        })
+        .is_err());
+        // An index past the end of the palette
+        assert!(Chunk::try_from(SavedChunk::Palette {
+            palette: vec![Material::STONE, Material::AIR],
+            bits: 2,
+            data: vec![!0; VOLUME * 2 / 64],
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn fill_whole_chunk() {
+        let mut chunk = random_chunk(1, 5);
+        let all = (UVec3::zeros(), UVec3::repeat(CHUNK_U - 1));
+        assert!(chunk.fill_box(all.0, all.1, Material::SAND));
+        check(&chunk, &vec![Material::SAND; VOLUME]);
+        assert_eq!(chunk.bits, 0);
+        assert_eq!(chunk.uniform(), Some(Material::SAND));
+        assert!(!chunk.fill_box(all.0, all.1, Material::SAND));
+    }
+
+    #[test]
+    fn replace_whole_chunk() {
+        let all = (UVec3::zeros(), UVec3::repeat(CHUNK_U - 1));
+        let mut chunk = random_chunk(2, 5);
+        let mut blocks = snapshot(&chunk);
+        let from = blocks[0];
+
+        // `to` isn't in the chunk yet, so this just changes the palette
+        let palette = chunk.palette.clone();
+        assert!(chunk.replace(all.0, all.1, from, Material::DIAMOND));
+        for b in blocks.iter_mut().filter(|b| **b == from) {
+            *b = Material::DIAMOND;
+        }
+        check(&chunk, &blocks);
+        assert_eq!(chunk.palette.len(), palette.len());
+
+        // Now it is, so every block has to change
+        let other = *blocks.iter().find(|&&b| b != Material::DIAMOND).unwrap();
+        assert!(chunk.replace(all.0, all.1, Material::DIAMOND, other));
+        for b in blocks.iter_mut().filter(|b| **b == Material::DIAMOND) {
+            *b = other;
+        }
+        check(&chunk, &blocks);
+
+        // There's no diamond left to replace
+        assert!(!chunk.replace(all.0, all.1, Material::DIAMOND, Material::GOLD));
+        check(&chunk, &blocks);
+    }
+
+    #[test]
+    fn paste_skip_air() {
+        let chunk = random_chunk(3, 5);
+        let s = chunk.copy_region(UVec3::new(3, 4, 5), UVec3::new(20, 30, 31));
+        let mut pasted = Chunk::filled(Material::SNOW);
+        // Hanging over the edge
+        let at = IVec3::new(-2, 10, 20);
+        assert!(pasted.paste_region(at, &s, true));
+        for x in 0..CHUNK_U {
+            for y in 0..CHUNK_U {
+                for z in 0..CHUNK_U {
+                    let b = UVec3::new(x, y, z);
+                    let src = b.map(|x| x as i32) - at;
+                    let m = if (0..3).all(|a| src[a] >= 0 && src[a] < s.size()[a] as i32) {
+                        s.block(src.map(|x| x as usize))
+                    } else {
+                        Material::AIR
+                    };
+                    let expected = if m == Material::AIR {
+                        Material::SNOW
+                    } else {
+                        m
+                    };
+                    assert_eq!(pasted.block(b), expected);
+                }
+            }
+        }
+    }
+}
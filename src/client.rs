@@ -2,7 +2,7 @@ use crate::client_aux::*;
 use crate::common::*;
 use crate::mesh::*;
 use crate::physics::*;
-use enum_iterator::IntoEnumIterator;
+use crate::world::{raycast, Hit};
 use glium::glutin::*;
 use glium::*;
 use glsl_include::Context as ShaderContext;
@@ -304,9 +304,7 @@ impl<'a> DrawStuff<'a> {
         ];
         let quad = glium::VertexBuffer::new(display, &quad).unwrap();
 
-        let mats = Material::into_enum_iter()
-            .map(|x| x.mat_data())
-            .collect::<Vec<MatData>>();
+        let mats = registry().mat_data();
         let mat_buf = glium::uniforms::UniformBuffer::empty_unsized_immutable(
             display,
             std::mem::size_of::<MatData>() * mats.len(),
@@ -381,23 +379,37 @@ pub struct Client<'font, 'p> {
     meshes: HashMap<IVec3, Mesh>,
     colliders: HashMap<IVec3, np::object::DefaultColliderHandle>,
     display: Display,
-    aux: (Sender<Message>, Receiver<Option<ClientMessage>>),
+    aux: (Sender<Message>, Receiver<AuxMessage>),
     time: f64,
     physics: Physics,
     player_handle: np::object::DefaultBodyHandle,
     player_c_handle: np::object::DefaultColliderHandle,
     config: Arc<ClientConfig>,
     inventory: Vec<(Material, usize)>,
+    /// Whether the break button is held down
+    breaking: bool,
+    /// The block we're breaking, and how long we've been at it
+    mining: Option<(IVec3, f64)>,
+    /// What was at each block before we last changed it, in case the server turns the edit down
+    /// without telling us what's really there
+    edits: HashMap<IVec3, Material>,
+    /// The world border, which the server won't let us past
+    border: Option<Border>,
     glyph_brush: GlyphBrush<'font, 'p>,
 }
 
 impl<'font, 'p> Client<'font, 'p> {
+    /// `welcome` is what the server said when we logged in on `conn`
     pub fn new(
         display: Display,
         config: Arc<ClientConfig>,
         conn: Connection,
-        player: Vec3,
+        welcome: Welcome,
     ) -> Self {
+        // The server decides which material has which id
+        set_registry(Registry::new(welcome.materials));
+
+        let player = welcome.spawn;
         let (to, from_them) = channel();
         let (to_them, from) = channel();
         let two = Arc::clone(&config);
@@ -428,12 +440,16 @@ impl<'font, 'p> Client<'font, 'p> {
             colliders: HashMap::with_capacity(config.game_config.draw_chunks.pow(3) / 2),
             display,
             aux: (to, from),
-            time: 0.0,
+            time: welcome.time,
             physics,
             player_handle,
             player_c_handle,
             config,
-            inventory: Vec::new(),
+            inventory: welcome.inventory,
+            breaking: false,
+            mining: None,
+            edits: HashMap::new(),
+            border: welcome.border,
             glyph_brush,
         }
     }
@@ -564,7 +580,7 @@ impl<'font, 'p> Client<'font, 'p> {
         for (m, n) in &self.inventory {
             let old_start = start;
             self.glyph_brush.queue(Section {
-                text: &format!("{:?} x {}", m, n),
+                text: &format!("{} x {}", m, n),
                 bounds: (resolution.0 as f32, resolution.1 as f32 / 2.0),
                 screen_position: (0.0, old_start),
                 scale: glium_glyph::glyph_brush::rusttype::Scale::uniform(if start == 40.0 {
@@ -604,18 +620,8 @@ impl<'font, 'p> Client<'font, 'p> {
             glutin::Event::DeviceEvent { event, .. } => {
                 match event {
                     // Left-click
-                    glutin::DeviceEvent::Button {
-                        button: 1,
-                        state: glutin::ElementState::Pressed,
-                    } => {
-                        if let Some(p) = self.trace(self.pos(), self.camera.dir, 16.0) {
-                            let old = self.set_block(p.cell, Material::Air);
-                            if let Some(x) = self.inventory.iter_mut().find(|x| x.0 == old) {
-                                x.1 += 1;
-                            } else {
-                                self.inventory.push((old, 1));
-                            }
-                        }
+                    glutin::DeviceEvent::Button { button: 1, state } => {
+                        self.breaking = state == glutin::ElementState::Pressed;
                     }
                     glutin::DeviceEvent::MouseWheel { delta } => {
                         let d = match delta {
@@ -638,8 +644,8 @@ impl<'font, 'p> Client<'font, 'p> {
                         button: 3,
                         state: glutin::ElementState::Pressed,
                     } => {
-                        if let Some(p) = self.trace(self.pos(), self.camera.dir, 16.0) {
-                            let b = p.cell + p.normal.map(|x| x as i32);
+                        if let Some(p) = self.trace(self.pos(), self.camera.dir, REACH) {
+                            let b = p.pos + p.normal;
                             let iso1 = self
                                 .physics
                                 .bodies
@@ -675,6 +681,23 @@ impl<'font, 'p> Client<'font, 'p> {
             }
             _ => (),
         });
+
+        // There's nothing past the world border, so keep the player from falling out of the world
+        if let Some(border) = self.border {
+            let body = self
+                .physics
+                .bodies
+                .rigid_body_mut(self.player_handle)
+                .unwrap();
+            let mut pos = *body.position();
+            let inside = border.clamp(pos.translation.vector);
+            if inside != pos.translation.vector {
+                pos.translation.vector = inside;
+                body.set_position(pos);
+                body.set_linear_velocity(Vec3::zeros());
+            }
+        }
+
         camera.update(
             delta,
             resolution,
@@ -685,9 +708,17 @@ impl<'font, 'p> Client<'font, 'p> {
         );
         self.camera = camera;
 
-        if let Ok(chunks) = self.aux.1.try_recv() {
-            // Only load chunks once per frame
-            self.load_chunks(chunks.unwrap());
+        if self.breaking {
+            self.mine(delta);
+        } else {
+            self.mining = None;
+        }
+
+        // Only load chunks once per frame
+        match self.aux.1.try_recv() {
+            Ok(AuxMessage::Chunks(chunks)) => self.load_chunks(chunks),
+            Ok(AuxMessage::Rejected(loc, tried, actual)) => self.undo_edit(loc, tried, actual),
+            _ => (),
         }
         self.aux.0.send(Message::PlayerMove(self.pos())).unwrap();
 
@@ -764,13 +795,22 @@ impl<'font, 'p> Client<'font, 'p> {
 
         self.aux.0.send(Message::Leave).unwrap();
         while let Ok(m) = self.aux.1.recv() {
-            if m.is_none() {
+            if let AuxMessage::Done = m {
                 break;
             }
         }
     }
 
+    /// Sets a block and tells the server about it. The server can turn it down later, see `undo_edit()`.
     pub fn set_block(&mut self, loc: IVec3, new: Material) -> Material {
+        let old = self.set_local_block(loc, new);
+        self.edits.insert(loc, old);
+        self.aux.0.send(Message::SetBlock(loc, new)).unwrap();
+        old
+    }
+
+    /// Sets a block without telling the server
+    fn set_local_block(&mut self, loc: IVec3, new: Material) -> Material {
         let chunk = world_to_chunk(loc.map(|x| x as f32));
         let in_chunk = in_chunk(loc.map(|x| x as f32));
 
@@ -778,23 +818,77 @@ impl<'font, 'p> Client<'font, 'p> {
         let old = chunk_rc.read().unwrap().block(in_chunk);
         chunk_rc.write().unwrap().set_block(in_chunk, new);
 
-        self.aux.0.send(Message::SetBlock(loc, new)).unwrap();
+        for i in chunks_touching(loc) {
+            self.remesh(i);
+        }
+
+        old
+    }
 
-        self.remesh(chunk);
-        for d in 0..3 {
-            if in_chunk[d] == 0 {
-                let mut i = chunk;
-                i[d] -= 1;
-                self.remesh(i);
+    /// Keeps breaking the block we're looking at, which takes as long as its hardness
+    fn mine(&mut self, delta: f64) {
+        let hit = match self.trace(self.pos(), self.camera.dir, REACH) {
+            Some(hit) => hit,
+            None => {
+                self.mining = None;
+                return;
             }
-            if in_chunk[d] == CHUNK_SIZE as usize - 1 {
-                let mut i = chunk;
-                i[d] += 1;
-                self.remesh(i);
+        };
+        let target = hit.pos;
+        let t = match self.mining {
+            Some((cell, t)) if cell == target => t + delta,
+            // We just started, or looked at a different block
+            _ => 0.0,
+        };
+
+        let hardness = hit.material.hardness();
+        if hardness >= 0.0 && t >= hardness as f64 {
+            let old = self.set_block(target, Material::AIR);
+            if let Some(d) = old.drops() {
+                self.give(d);
             }
+            self.mining = None;
+        } else {
+            self.mining = Some((target, t));
         }
+    }
 
-        old
+    /// Rolls back an edit the server turned down, including what it did to our inventory
+    fn undo_edit(&mut self, loc: IVec3, tried: Material, actual: Option<Material>) {
+        // If the server doesn't know what's there, the best we can do is put back what we had
+        let actual = actual.or_else(|| self.edits.get(&loc).cloned());
+        self.edits.remove(&loc);
+        if tried == Material::AIR {
+            // We picked up whatever we broke, so put it back
+            if let Some(d) = actual.and_then(|x| x.drops()) {
+                self.take(d);
+            }
+        } else {
+            self.give(tried);
+        }
+        let chunk = world_to_chunk(loc.map(|x| x as f32));
+        if let (Some(actual), true) = (actual, self.chunks.contains_key(&chunk)) {
+            self.set_local_block(loc, actual);
+        }
+    }
+
+    /// Adds one of `m` to the inventory
+    fn give(&mut self, m: Material) {
+        if let Some(x) = self.inventory.iter_mut().find(|x| x.0 == m) {
+            x.1 += 1;
+        } else {
+            self.inventory.push((m, 1));
+        }
+    }
+
+    /// Removes one of `m` from the inventory, if we have any
+    fn take(&mut self, m: Material) {
+        if let Some(i) = self.inventory.iter().position(|x| x.0 == m) {
+            self.inventory[i].1 -= 1;
+            if self.inventory[i].1 == 0 {
+                self.inventory.remove(i);
+            }
+        }
     }
 
     fn remesh(&mut self, chunk: IVec3) -> Option<()> {
@@ -822,14 +916,7 @@ impl<'font, 'p> Client<'font, 'p> {
             .mesher
             .mesh(&chunk_rc.read().unwrap(), neighbors, true);
 
-        if !verts.is_empty() {
-            let v_physics: Vec<_> = verts.iter().map(|x| na::Point3::from(x.pos)).collect();
-            let i_physics: Vec<_> = (0..v_physics.len() / 3)
-                .map(|x| na::Point3::new(x * 3, x * 3 + 1, x * 3 + 2))
-                .collect();
-            let chunk_shape =
-                nc::shape::ShapeHandle::new(nc::shape::TriMesh::new(v_physics, i_physics, None));
-
+        if let Some(chunk_shape) = collider(&verts) {
             let chunk_collider = np::object::ColliderDesc::new(chunk_shape)
                 .translation(chunk.map(|x| x as f32) * CHUNK_SIZE)
                 .build(self.physics.ground);
@@ -856,6 +943,11 @@ impl<'font, 'p> Client<'font, 'p> {
 
         for (i, v, v2, s, c) in chunks {
             // TODO indices
+            // This might be a remesh of a chunk we already have, after another player changed it
+            let new = !self.meshes.contains_key(&i);
+            if let Some(handle) = self.colliders.remove(&i) {
+                self.physics.colliders.remove(handle);
+            }
             if let Some(chunk_shape) = s {
                 let chunk_collider = np::object::ColliderDesc::new(chunk_shape)
                     .translation(i.map(|x| x as f32) * CHUNK_SIZE)
@@ -870,7 +962,7 @@ impl<'font, 'p> Client<'font, 'p> {
                 v2,
                 i.map(|x| x as f32) * CHUNK_SIZE,
                 Vec3::new(0.0, 0.0, 0.0),
-                true,
+                new,
             );
 
             self.chunks.insert(i, c);
@@ -882,6 +974,7 @@ impl<'font, 'p> Client<'font, 'p> {
     /// This is the client function, so it won't store it anywhere or anything, that's the server's job.
     pub fn unload(&mut self, idx: IVec3) {
         self.chunks.remove(&idx);
+        self.edits.retain(|&loc, _| split_block(loc).0 != idx);
         self.meshes.remove(&idx);
         if let Some(handle) = self.colliders.remove(&idx) {
             self.physics.colliders.remove(handle);
@@ -900,39 +993,13 @@ impl<'font, 'p> Client<'font, 'p> {
     }
 }
 
-pub struct Intersection {
-    pub pos: Vec3,
-    pub cell: IVec3,
-    pub t: f32,
-    pub normal: Vec3,
-}
-
 impl Client<'_, '_> {
-    pub fn trace(&self, ro: Vec3, rd: Vec3, max_t: f32) -> Option<Intersection> {
-        let ray = nc::query::Ray::new(ro.into(), rd);
-
-        let g = nc::pipeline::object::CollisionGroups::default();
-        let it = self
-            .physics
-            .geom
-            .interferences_with_ray(&self.physics.colliders, &ray, &g);
-
-        let first = it.filter(|x| x.0 != self.player_c_handle).min_by(|a, b| {
-            a.2.toi
-                .partial_cmp(&b.2.toi)
-                .unwrap_or(std::cmp::Ordering::Equal)
-        })?;
-        let pos = ro + first.2.toi * rd;
-
-        if first.2.toi <= max_t {
-            Some(Intersection {
-                pos,
-                cell: (pos - 0.1 * first.2.normal).map(|x| x as i32 - if x < 0.0 { 1 } else { 0 }),
-                t: first.2.toi,
-                normal: first.2.normal,
-            })
-        } else {
-            None
-        }
+    pub fn trace(&self, ro: Vec3, rd: Vec3, max_t: f32) -> Option<Hit> {
+        raycast(ro, rd, max_t, |p| {
+            let (chunk, idx) = split_block(p);
+            self.chunks
+                .get(&chunk)
+                .map(|c| c.read().unwrap().block(idx))
+        })
     }
 }
@@ -1,6 +1,7 @@
 // This is the client auxilary thread, which is in charge of recieving chunks, meshing them, and sending them to the client thread.
 
 use crate::common::*;
+use crate::light::*;
 use crate::mesh::Vertex;
 use crate::mesh::*;
 use std::collections::HashMap;
@@ -16,10 +17,27 @@ pub type ClientMessage = Vec<(
     Arc<RwLock<Chunk>>,
 )>;
 
+/// What the aux thread sends to the client thread
+pub enum AuxMessage {
+    Chunks(ClientMessage),
+    /// The server turned down one of our edits: `(block, what_we_tried, what's_really_there)`
+    Rejected(IVec3, Material, Option<Material>),
+    /// A response to Message::Leave, saying we're ready to go
+    Done,
+}
+
+/// Queues up chunks to be meshed again, if they aren't already
+fn remesh(indices: &mut Vec<IVec3>, chunks: impl IntoIterator<Item = IVec3>) {
+    for i in chunks {
+        if !indices.contains(&i) {
+            indices.push(i);
+        }
+    }
+}
+
 pub fn client_aux_thread(
     server: Connection,
-    // A None ClientMessage is a response to Message::Leave, saying we're ready to go.
-    client: (Sender<Option<ClientMessage>>, Receiver<Message>),
+    client: (Sender<AuxMessage>, Receiver<Message>),
     mut player: Vec3,
     config: Arc<ClientConfig>,
 ) {
@@ -40,6 +58,8 @@ pub fn client_aux_thread(
                         server
                             .send(Message::SetBlock(p, b))
                             .expect("Disconnected from server");
+                        // The client thread already changed it, but the light is up to us
+                        remesh(&mut indices, relight(&chunk_map, p));
                     }
                     Message::Leave => {
                         server
@@ -50,6 +70,7 @@ pub fn client_aux_thread(
                                 break;
                             }
                         }
+                        client.0.send(AuxMessage::Done).unwrap();
                         return;
                     }
                     x => panic!("Aux thread recieved {:?} from the client thread!", x),
@@ -118,24 +139,13 @@ pub fn client_aux_thread(
                     )
                 })
                 .map(|(loc, mesh, mesh_p2, chunk)| {
-                    if !mesh.is_empty() {
-                        let v_physics: Vec<_> =
-                            mesh.iter().map(|x| na::Point3::from(x.pos)).collect();
-                        let i_physics: Vec<_> = (0..v_physics.len() / 3)
-                            .map(|x| na::Point3::new(x * 3, x * 3 + 1, x * 3 + 2))
-                            .collect();
-                        let chunk_shape = nc::shape::ShapeHandle::new(nc::shape::TriMesh::new(
-                            v_physics, i_physics, None,
-                        ));
-                        (loc, mesh, mesh_p2, Some(chunk_shape), chunk)
-                    } else {
-                        (loc, mesh, mesh_p2, None, chunk)
-                    }
+                    let chunk_shape = collider(&mesh);
+                    (loc, mesh, mesh_p2, chunk_shape, chunk)
                 })
                 .collect();
             let r = meshed.iter().map(|x| x.0).collect::<HashSet<_>>();
             indices.retain(|x| !r.contains(x));
-            client.0.send(Some(meshed)).unwrap();
+            client.0.send(AuxMessage::Chunks(meshed)).unwrap();
             // println!("Meshing took {} ms/chunk", timer.elapsed_ms() as f64 / r.len() as f64);
             counter += 1;
         }
@@ -149,14 +159,56 @@ pub fn client_aux_thread(
                         chunks.iter().map(|x| x.0).collect::<Vec<IVec3>>()
                     );
                     */
-                    indices.extend(chunks.iter().map(|x| x.0));
+                    let locs: Vec<IVec3> = chunks.iter().map(|x| x.0).collect();
+                    // These are new versions of chunks we have if the server changed them,
+                    // and then the sides of their neighbors facing them might have changed too
+                    for i in locs.iter().filter(|i| chunk_map.contains_key(i)) {
+                        remesh(
+                            &mut indices,
+                            neighbors(*i)
+                                .into_iter()
+                                .filter(|x| chunk_map.contains_key(x)),
+                        );
+                    }
+                    indices.extend(&locs);
                     chunk_map.extend(
                         chunks
                             .into_iter()
                             .map(|(x, y)| (x, Arc::new(RwLock::new(y)))),
                     );
+                    for i in locs {
+                        remesh(&mut indices, light_chunk(&chunk_map, i));
+                    }
                     counter = 100; // Trigger a re-sort
                 }
+                Message::BlockRejected(loc, tried, actual) => {
+                    // The client thread puts it back, but we need to fix the light
+                    let p = loc.map(|x| x as f32);
+                    if let (Some(chunk), Some(actual)) = (chunk_map.get(&world_to_chunk(p)), actual)
+                    {
+                        chunk.write().unwrap().set_block(in_chunk(p), actual);
+                        remesh(&mut indices, relight(&chunk_map, loc));
+                    }
+                    client
+                        .0
+                        .send(AuxMessage::Rejected(loc, tried, actual))
+                        .unwrap();
+                }
+                Message::BlockChanges(changes) => {
+                    for (loc, b) in changes {
+                        let p = loc.map(|x| x as f32);
+                        if let Some(chunk) = chunk_map.get(&world_to_chunk(p)) {
+                            chunk.write().unwrap().set_block(in_chunk(p), b);
+                            remesh(
+                                &mut indices,
+                                chunks_touching(loc)
+                                    .into_iter()
+                                    .filter(|i| chunk_map.contains_key(i)),
+                            );
+                            remesh(&mut indices, relight(&chunk_map, loc));
+                        }
+                    }
+                }
                 _ => (),
             }
         }
@@ -8,12 +8,22 @@ pub use nalgebra::{Isometry3, Point3, Scalar, Unit, Vector3};
 pub use ncollide3d as nc;
 pub use np::object::Body;
 pub use nphysics3d as np;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
 use std::sync::mpsc::*;
 
 pub use crate::config::*;
 
 pub const CHUNK_SIZE: f32 = 32.0;
 pub const REGION_SIZE: i32 = 4;
+/// The port dedicated servers listen on if they aren't told otherwise
+pub const DEFAULT_PORT: u16 = 40_610;
+/// How far away, in meters, a player can break or place blocks
+pub const REACH: f32 = 16.0;
+/// Bump this whenever `Message` or anything in it changes how it's serialized
+pub const PROTOCOL_VERSION: u32 = 9;
 
 // Shorthands to match GLSL
 pub type IVec3 = Vector3<i32>;
@@ -60,14 +70,103 @@ pub fn in_chunk(world: Vec3) -> UVec3 {
     })
 }
 
+/// The chunk that block `block` is in, and where it is in that chunk
+pub fn split_block(block: IVec3) -> (IVec3, UVec3) {
+    let size = CHUNK_SIZE as i32;
+    (
+        block.map(|x| x.div_euclid(size)),
+        block.map(|x| x.rem_euclid(size) as usize),
+    )
+}
+
+/// The edges of a finite world, as a box of chunks.
+/// Nothing outside it is generated or loaded, and players can't go there or change anything there.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Border {
+    /// The lowest chunk inside along each axis, so `min.y` is the lowest chunk height
+    pub min: IVec3,
+    /// The highest chunk inside along each axis
+    pub max: IVec3,
+}
+
+impl Border {
+    pub fn contains(&self, chunk: IVec3) -> bool {
+        (0..3).all(|a| chunk[a] >= self.min[a] && chunk[a] <= self.max[a])
+    }
+
+    /// The closest position to `pos` that's inside the border
+    pub fn clamp(&self, pos: Vec3) -> Vec3 {
+        let lo = self.min.map(|x| x as f32 * CHUNK_SIZE);
+        // Just short of the next chunk, which is outside
+        let hi = self.max.map(|x| (x + 1) as f32 * CHUNK_SIZE - 0.001);
+        Vec3::new(
+            pos.x.max(lo.x).min(hi.x),
+            pos.y.max(lo.y).min(hi.y),
+            pos.z.max(lo.z).min(hi.z),
+        )
+    }
+}
+
+fn splitmix(x: u64) -> u64 {
+    let mut x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+fn unit(x: u64) -> f64 {
+    (x >> 11) as f64 / (1u64 << 53) as f64
+}
+
+fn mix(seed: u32, vals: &[i64]) -> u64 {
+    vals.iter()
+        .fold(seed as u64, |x, &v| splitmix(x.wrapping_add(v as u64)))
+}
+
+/// A random number from 0 to 1 that only depends on its inputs (it's splitmix64)
+pub fn hash(seed: u32, vals: &[i64]) -> f64 {
+    unit(mix(seed, vals))
+}
+
+/// Random numbers for world generation, which only depend on the seed and position they started with
+pub struct Rand(u64);
+
+impl Rand {
+    pub fn new(seed: u32, vals: &[i64]) -> Self {
+        Rand(mix(seed, vals))
+    }
+
+    /// From 0 to 1
+    pub fn next(&mut self) -> f64 {
+        self.0 = splitmix(self.0);
+        unit(self.0)
+    }
+
+    /// From `lo` up to but not including `hi`
+    pub fn range(&mut self, lo: i32, hi: i32) -> i32 {
+        (lo + (self.next() * (hi - lo) as f64) as i32).min(hi - 1)
+    }
+}
+
 pub use crate::chunk::*;
 pub use crate::material::*;
 
 pub enum Connection {
     Local(Sender<Message>, Receiver<Message>),
-    // TODO some sort of buffered TCP stream inplementation of Connection
+    /// A non-blocking TCP stream, plus a buffer of bytes we've read that don't make a whole frame yet,
+    /// and a queue of bytes we haven't been able to send yet
+    Tcp(TcpStream, RefCell<Vec<u8>>, RefCell<Vec<u8>>),
 }
 
+/// How long either side waits for the other during the handshake
+pub const LOGIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// The largest frame we'll accept, so a bad length prefix can't make us buffer forever
+const MAX_FRAME: usize = 1 << 27;
+
+/// The most we'll queue up for a peer that isn't reading what we send, before we hang up on them
+const MAX_QUEUE: usize = 1 << 28;
+
 impl Connection {
     /// Create a two new Local connections - (client, server)
     pub fn local() -> (Connection, Connection) {
@@ -78,35 +177,408 @@ impl Connection {
         (client, server)
     }
 
-    /// Equivalent to Sender::send() but as an option
+    /// Wrap a connected TCP stream, switching it to non-blocking mode
+    pub fn tcp(stream: TcpStream) -> std::io::Result<Connection> {
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+        Ok(Connection::Tcp(
+            stream,
+            RefCell::new(Vec::new()),
+            RefCell::new(Vec::new()),
+        ))
+    }
+
+    /// Connect to a server over TCP
+    pub fn connect(addr: impl ToSocketAddrs) -> std::io::Result<Connection> {
+        Connection::tcp(TcpStream::connect(addr)?)
+    }
+
+    /// Introduce ourselves to the server and wait until it lets us in or turns us away
+    pub fn login(&self, name: String, spawn: Option<Vec3>) -> Result<Welcome, String> {
+        self.send(Message::Hello {
+            version: PROTOCOL_VERSION,
+            name,
+            spawn,
+        })
+        .ok_or_else(|| "Disconnected from server".to_string())?;
+
+        let timer = std::time::Instant::now();
+        while timer.elapsed() < LOGIN_TIMEOUT {
+            match self.recv() {
+                Some(Message::Welcome(w)) => return Ok(w),
+                Some(Message::Reject(why)) => return Err(why),
+                Some(m) => return Err(format!("Server sent {:?} before letting us in", m)),
+                None => std::thread::sleep(std::time::Duration::from_millis(5)),
+            }
+        }
+        Err("Timed out waiting for the server".to_string())
+    }
+
+    /// Equivalent to Sender::send() but as an option - doesn't block.
+    /// Over TCP, whatever doesn't fit in the socket is queued and sent by later calls to `send` or `recv`.
     pub fn send(&self, m: Message) -> Option<()> {
         match self {
             Connection::Local(to, _from) => to.send(m).ok(),
+            Connection::Tcp(stream, _buf, queue) => {
+                let mut queue = queue.borrow_mut();
+                queue.extend_from_slice(&encode_frame(&m)?);
+                if queue.len() > MAX_QUEUE {
+                    // They aren't keeping up, so hang up on them; `recv` returns `Leave` after this
+                    queue.clear();
+                    stream.shutdown(Shutdown::Both).ok();
+                    return None;
+                }
+                write_available(stream, &mut queue).ok()
+            }
         }
     }
 
-    /// Equivalent to Receiver::try_recv() but as an option - doesn't block
+    /// Equivalent to Receiver::try_recv() but as an option - doesn't block.
+    /// If the other end has gone away, this returns `Message::Leave` as if they'd said goodbye.
     pub fn recv(&self) -> Option<Message> {
         match self {
-            Connection::Local(_to, from) => from.try_recv().ok(),
+            Connection::Local(_to, from) => match from.try_recv() {
+                Ok(m) => Some(m),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => Some(Message::Leave),
+            },
+            Connection::Tcp(stream, buf, queue) => {
+                // If this fails, they've hung up, which reading will find out too
+                write_available(stream, &mut queue.borrow_mut()).ok();
+                let mut buf = buf.borrow_mut();
+                let m = match decode_frame(&mut buf) {
+                    Ok(None) => {
+                        let open = read_available(stream, &mut buf);
+                        match decode_frame(&mut buf) {
+                            Ok(None) if !open => return Some(Message::Leave),
+                            m => m,
+                        }
+                    }
+                    m => m,
+                };
+                m.unwrap_or_else(|e| {
+                    // We've lost track of what they're saying, and there's no way to get it back, so give up on the connection
+                    println!("Hanging up, since we couldn't decode what they sent: {}", e);
+                    buf.clear();
+                    stream.shutdown(Shutdown::Both).ok();
+                    Some(Message::Leave)
+                })
+            }
         }
     }
 }
 
-#[derive(Debug)]
+/// A frame is a little-endian `u32` length, then a flag byte saying whether it's zstd-compressed, then a bincoded `Message`.
+/// The length counts the flag byte and the payload.
+fn encode_frame(m: &Message) -> Option<Vec<u8>> {
+    let payload = bincode::serialize(m).ok()?;
+    // Chunks are big and very compressible, everything else is tiny
+    let (flag, payload) = match m {
+        Message::Chunks(_) => (1, zstd::stream::encode_all(&payload[..], 3).ok()?),
+        _ => (0, payload),
+    };
+    let len = payload.len() + 1;
+    if len > MAX_FRAME {
+        return None;
+    }
+    let mut frame = Vec::with_capacity(len + 4);
+    frame.extend_from_slice(&(len as u32).to_le_bytes());
+    frame.push(flag);
+    frame.extend_from_slice(&payload);
+    Some(frame)
+}
+
+/// Pops the first frame off the front of `buf` if it's all there.
+/// It's an error if it can't be decoded, since then we can't trust anything that comes after it either.
+fn decode_frame(buf: &mut Vec<u8>) -> Result<Option<Message>, String> {
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+    let mut len = [0; 4];
+    len.copy_from_slice(&buf[0..4]);
+    let len = u32::from_le_bytes(len) as usize;
+    if len == 0 || len > MAX_FRAME {
+        return Err(format!("bad frame length {}", len));
+    }
+    if buf.len() < len + 4 {
+        return Ok(None);
+    }
+    let frame: Vec<u8> = buf.drain(0..len + 4).skip(4).collect();
+    let payload = match frame[0] {
+        0 => frame[1..].to_vec(),
+        1 => zstd::stream::decode_all(&frame[1..]).map_err(|e| format!("bad zstd: {}", e))?,
+        flag => return Err(format!("unknown frame flag {}", flag)),
+    };
+    bincode::deserialize(&payload)
+        .map(Some)
+        .map_err(|e| format!("bad message: {}", e))
+}
+
+/// Reads whatever's waiting on the socket into `buf` without blocking.
+/// Returns false if the socket's been closed.
+fn read_available(mut stream: &TcpStream, buf: &mut Vec<u8>) -> bool {
+    let mut tmp = [0; 4096];
+    loop {
+        match stream.read(&mut tmp) {
+            Ok(0) => return false,
+            Ok(n) => buf.extend_from_slice(&tmp[..n]),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return true,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(_) => return false,
+        }
+    }
+}
+
+/// Writes as much of `queue` as the socket will take without blocking, and removes it from the queue
+fn write_available(mut stream: &TcpStream, queue: &mut Vec<u8>) -> std::io::Result<()> {
+    let mut sent = 0;
+    let result = loop {
+        if sent == queue.len() {
+            break Ok(());
+        }
+        match stream.write(&queue[sent..]) {
+            Ok(0) => break Err(ErrorKind::WriteZero.into()),
+            Ok(n) => sent += n,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => break Ok(()),
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => break Err(e),
+        }
+    };
+    queue.drain(..sent);
+    result
+}
+
+/// What the server tells a player when it lets them in
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Welcome {
+    pub id: usize,
+    pub seed: u32,
+    pub spawn: Vec3,
+    /// The world's time, in seconds
+    pub time: f64,
+    /// The server's material registry, since the ids depend on the world
+    pub materials: Vec<MaterialDef>,
+    /// The world border, if the world has one
+    pub border: Option<Border>,
+    /// What the player had when they last left this world
+    pub inventory: Vec<(Material, usize)>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub enum Message {
+    // The handshake comes first and must keep its layout across versions, so mismatched clients can always be turned away
+    /// Client -> server, the first thing sent on a connection
+    Hello {
+        version: u32,
+        name: String,
+        spawn: Option<Vec3>,
+    },
+    /// Server -> client, in response to an acceptable `Hello`
+    Welcome(Welcome),
+    /// Server -> client, in response to a bad `Hello`; the connection is dropped afterwards
+    Reject(String),
     PlayerMove(Vec3),
     Chunks(Vec<(IVec3, Chunk)>),
     SetBlock(IVec3, Material),
+    /// Server -> client, in response to a `SetBlock` it wouldn't allow: `(block, what_they_tried, what's_really_there)`.
+    /// The last one is `None` if the server doesn't have that chunk loaded.
+    BlockRejected(IVec3, Material, Option<Material>),
+    /// Server -> client, blocks other players changed, as `(block, new_material)`
+    BlockChanges(Vec<(IVec3, Material)>),
     Leave,
 }
 
 #[derive(Debug)]
 pub enum ChunkMessage {
     Done,
-    UpdateChunks(Vec<IVec3>),
     LoadChunks(Vec<IVec3>),
     // Chunks(Vec<(IVec3, Chunk)>),
     UnloadChunk(IVec3, Chunk),
     Players(Vec<Vec3>),
+    /// Writes every chunk that's been unloaded so far to disk, and keeps going
+    Flush,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::time::{Duration, Instant};
+
+    /// A client and server connected over loopback
+    fn pair() -> (Connection, Connection) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = Connection::connect(listener.local_addr().unwrap()).unwrap();
+        let server = Connection::tcp(listener.accept().unwrap().0).unwrap();
+        (client, server)
+    }
+
+    /// Waits for a message on `to`, while `from` keeps sending what it has queued
+    fn wait(from: Option<&Connection>, to: &Connection) -> Message {
+        let timer = Instant::now();
+        while timer.elapsed() < LOGIN_TIMEOUT {
+            if let Some(from) = from {
+                assert!(from.recv().is_none());
+            }
+            if let Some(m) = to.recv() {
+                return m;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        panic!("Timed out waiting for a message");
+    }
+
+    /// Random blocks, so it doesn't compress down to nothing
+    fn noise_chunk(seed: u32) -> Chunk {
+        Chunk::full(&|p| {
+            Material((hash(seed, &[p.x as i64, p.y as i64, p.z as i64]) * 14.0) as u16)
+        })
+    }
+
+    fn queued(conn: &Connection) -> usize {
+        match conn {
+            Connection::Tcp(_, _, queue) => queue.borrow().len(),
+            Connection::Local(_, _) => 0,
+        }
+    }
+
+    #[test]
+    fn small_message() {
+        let (client, server) = pair();
+        client
+            .send(Message::PlayerMove(Vec3::new(1.0, -2.0, 3.5)))
+            .unwrap();
+        match wait(Some(&client), &server) {
+            Message::PlayerMove(p) => assert_eq!(p, Vec3::new(1.0, -2.0, 3.5)),
+            m => panic!("Got {:?}", m),
+        }
+    }
+
+    #[test]
+    fn large_message() {
+        let (client, server) = pair();
+        let chunks: Vec<(IVec3, Chunk)> = (0..64)
+            .map(|i| (IVec3::new(i, -i, 0), noise_chunk(i as u32)))
+            .collect();
+        server.send(Message::Chunks(chunks.clone())).unwrap();
+        match wait(Some(&server), &client) {
+            Message::Chunks(got) => {
+                assert_eq!(got.len(), chunks.len());
+                for ((p, a), (q, b)) in chunks.iter().zip(&got) {
+                    assert_eq!(p, q);
+                    for x in 0..CHUNK_SIZE as usize {
+                        for y in 0..CHUNK_SIZE as usize {
+                            for z in 0..CHUNK_SIZE as usize {
+                                let idx = UVec3::new(x, y, z);
+                                assert_eq!(a.block(idx), b.block(idx));
+                            }
+                        }
+                    }
+                }
+            }
+            m => panic!("Got {:?}", m),
+        }
+    }
+
+    #[test]
+    fn queues_instead_of_blocking() {
+        let (client, server) = pair();
+        let chunk = noise_chunk(0);
+        // The client isn't reading yet, so the socket fills up and the rest has to wait in the queue
+        let mut sent = 0;
+        while queued(&server) == 0 {
+            assert!(sent < 10_000, "The socket never filled up");
+            server
+                .send(Message::Chunks(vec![(
+                    IVec3::new(sent, 0, 0),
+                    chunk.clone(),
+                )]))
+                .unwrap();
+            sent += 1;
+        }
+        for i in 0..sent {
+            match wait(Some(&server), &client) {
+                Message::Chunks(got) => assert_eq!(got[0].0, IVec3::new(i, 0, 0)),
+                m => panic!("Got {:?}", m),
+            }
+        }
+        assert_eq!(queued(&server), 0);
+    }
+
+    #[test]
+    fn split_frame() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut raw = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let server = Connection::tcp(listener.accept().unwrap().0).unwrap();
+
+        let frame = encode_frame(&Message::SetBlock(IVec3::new(-4, 5, 6), Material::GOLD)).unwrap();
+        // Split inside the length, then inside the payload
+        let (a, rest) = frame.split_at(2);
+        let (b, c) = rest.split_at(5);
+        for part in &[a, b] {
+            raw.write_all(part).unwrap();
+            std::thread::sleep(Duration::from_millis(20));
+            assert!(server.recv().is_none());
+        }
+        raw.write_all(c).unwrap();
+        match wait(None, &server) {
+            Message::SetBlock(p, m) => {
+                assert_eq!(p, IVec3::new(-4, 5, 6));
+                assert_eq!(m, Material::GOLD);
+            }
+            m => panic!("Got {:?}", m),
+        }
+    }
+
+    #[test]
+    fn hang_up() {
+        let (client, server) = pair();
+        drop(client);
+        let timer = Instant::now();
+        loop {
+            match server.recv() {
+                Some(Message::Leave) => break,
+                Some(m) => panic!("Got {:?}", m),
+                None => assert!(timer.elapsed() < LOGIN_TIMEOUT, "Never noticed the hang-up"),
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn corrupt_frame() {
+        let good = encode_frame(&Message::PlayerMove(Vec3::new(1.0, 2.0, 3.0))).unwrap();
+        let frame = |flag: u8, payload: &[u8]| {
+            let mut f = ((payload.len() + 1) as u32).to_le_bytes().to_vec();
+            f.push(flag);
+            f.extend_from_slice(payload);
+            f
+        };
+        // A message that doesn't exist, something that isn't zstd, a flag we don't know, and a bad length
+        let bad = [
+            frame(0, &[0xff; 8]),
+            frame(1, &[1, 2, 3, 4]),
+            frame(7, &good[5..]),
+            vec![0; 8],
+        ];
+        for bad in &bad {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut raw = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let server = Connection::tcp(listener.accept().unwrap().0).unwrap();
+
+            // What comes before it is fine, but nothing after it gets through
+            raw.write_all(&[&good[..], bad, &good[..]].concat())
+                .unwrap();
+            assert!(matches!(wait(None, &server), Message::PlayerMove(_)));
+            assert!(matches!(wait(None, &server), Message::Leave));
+
+            // And they get hung up on
+            raw.set_read_timeout(Some(LOGIN_TIMEOUT)).unwrap();
+            match raw.read(&mut [0; 16]) {
+                Ok(0) => (),
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionReset => (),
+                r => panic!("Still connected: {:?}", r),
+            }
+        }
+    }
 }
@@ -1,4 +1,7 @@
+use crate::common::Border;
+use crate::generator::WorldType;
 use crate::mesh::Mesher;
+use crate::terrain::GenConfig;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
@@ -8,6 +11,40 @@ pub struct GameConfig {
     pub draw_chunks: usize, // The number of chunks to draw in every direction
     pub batch_size: usize,  // The number of chunks to load per batch
     pub save_chunks: bool,
+    /// The name of the world to play in, which is created if it doesn't exist yet
+    #[serde(default = "default_world")]
+    pub world: String,
+    /// The seed for new worlds; if it's `None`, they get a random one
+    #[serde(default)]
+    pub seed: Option<u32>,
+    /// Terrain generator settings for new worlds
+    #[serde(default)]
+    pub gen: GenConfig,
+    /// What kind of world new worlds are
+    #[serde(default)]
+    pub world_type: WorldType,
+    /// The world border for new worlds, if they should have one
+    #[serde(default)]
+    pub border: Option<Border>,
+    /// How often the server saves chunks that have changed, in seconds
+    #[serde(default = "default_autosave")]
+    pub autosave: u64,
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        GameConfig {
+            draw_chunks: 16,
+            batch_size: 64,
+            save_chunks: true,
+            world: default_world(),
+            seed: None,
+            gen: GenConfig::default(),
+            world_type: WorldType::default(),
+            border: None,
+            autosave: default_autosave(),
+        }
+    }
 }
 
 /// Config for just the client
@@ -19,5 +56,34 @@ pub struct ClientConfig {
 
     pub keycodes: crate::input::KeyCodes,
 
+    /// What other players see us as
+    #[serde(default = "default_name")]
+    pub name: String,
+
     pub game_config: Arc<GameConfig>,
 }
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            mesher: Mesher::Greedy,
+            wireframe: false,
+            batch_size: 16,
+            keycodes: crate::input::DEFAULT_KEY_CODES,
+            name: default_name(),
+            game_config: Arc::new(GameConfig::default()),
+        }
+    }
+}
+
+fn default_autosave() -> u64 {
+    300
+}
+
+fn default_world() -> String {
+    "default".to_string()
+}
+
+fn default_name() -> String {
+    std::env::var("USER").unwrap_or_else(|_| "Player".to_string())
+}
@@ -0,0 +1,297 @@
+use crate::biome::*;
+use crate::common::*;
+use crate::terrain::Gen;
+
+/// The top block of a column, where features start
+#[derive(Clone, Copy, Debug)]
+pub struct Site {
+    pub pos: IVec3,
+    pub material: Material,
+    pub biome: Biome,
+}
+
+/// One block of a feature
+pub struct Place {
+    pub pos: IVec3,
+    pub material: Material,
+    /// Whether it can replace solid blocks, or only air
+    pub replace: bool,
+}
+
+impl Place {
+    fn new(pos: IVec3, material: Material, replace: bool) -> Self {
+        Place {
+            pos,
+            material,
+            replace,
+        }
+    }
+}
+
+/// Something placed on top of the terrain, like a tree.
+/// Features can reach into neighboring chunks, so they have to be deterministic:
+/// `place` can only look at the terrain through `Gen` (never the world), and only use `rand` for randomness.
+pub trait Feature: Send + Sync {
+    /// The average number of times it's placed in a chunk in `biome`
+    fn frequency(&self, biome: Biome) -> f64;
+
+    /// How far it can reach from its site, in blocks along each axis. It can't be more than `CHUNK_SIZE`.
+    fn radius(&self) -> i32;
+
+    /// The blocks it's made of, if it's placed at `site`; it can return nothing if it doesn't fit there
+    fn place(&self, gen: &Gen, site: Site, rand: &mut Rand) -> Vec<Place>;
+}
+
+pub fn default_features() -> Vec<Box<dyn Feature>> {
+    vec![
+        Box::new(Tree::Oak),
+        Box::new(Tree::Pine),
+        Box::new(Tree::Shrub),
+        Box::new(Boulder),
+        Box::new(Ruins),
+        Box::new(Lake),
+    ]
+}
+
+/// Whether things can grow on `site`
+fn fertile(site: Site) -> bool {
+    site.material == site.biome.data().surface && site.material != Material::SAND
+}
+
+pub enum Tree {
+    Oak,
+    Pine,
+    Shrub,
+}
+
+impl Feature for Tree {
+    fn frequency(&self, biome: Biome) -> f64 {
+        let trees = biome.data().trees;
+        match (self, biome) {
+            (Tree::Oak, Biome::Plains) | (Tree::Oak, Biome::Forest) => 6.0 * trees,
+            (Tree::Pine, Biome::Forest) => 2.0 * trees,
+            (Tree::Pine, Biome::Tundra) | (Tree::Pine, Biome::Mountains) => 12.0 * trees,
+            (Tree::Shrub, Biome::Plains) | (Tree::Shrub, Biome::Forest) => 2.0,
+            _ => 0.0,
+        }
+    }
+
+    fn radius(&self) -> i32 {
+        match self {
+            Tree::Oak => 11,
+            Tree::Pine => 13,
+            Tree::Shrub => 2,
+        }
+    }
+
+    fn place(&self, _gen: &Gen, site: Site, rand: &mut Rand) -> Vec<Place> {
+        if !fertile(site) {
+            return Vec::new();
+        }
+        let base = site.pos + IVec3::new(0, 1, 0);
+        let mut ret = Vec::new();
+
+        let height = match self {
+            Tree::Oak => rand.range(3, 8),
+            Tree::Pine => rand.range(6, 11),
+            Tree::Shrub => 1,
+        };
+        for y in 0..height {
+            ret.push(Place::new(base + IVec3::new(0, y, 0), Material::WOOD, true));
+        }
+        let top = base + IVec3::new(0, height, 0);
+
+        match self {
+            Tree::Oak => {
+                let w = rand.range(1, 3);
+                for x in -w..=w {
+                    for z in -w..=w {
+                        for y in -w..w {
+                            ret.push(Place::new(top + IVec3::new(x, y, z), Material::LEAF, false));
+                        }
+                    }
+                }
+            }
+            Tree::Pine => {
+                // A cone of leaves, starting a couple blocks up the trunk
+                let bottom = 2;
+                for y in bottom..=height {
+                    let r = (height - y) * 3 / (height - bottom).max(1);
+                    for x in -r..=r {
+                        for z in -r..=r {
+                            if x * x + z * z <= r * r + 1 {
+                                ret.push(Place::new(
+                                    base + IVec3::new(x, y, z),
+                                    Material::LEAF,
+                                    false,
+                                ));
+                            }
+                        }
+                    }
+                }
+            }
+            Tree::Shrub => {
+                for x in -1..=1 {
+                    for z in -1..=1 {
+                        for y in 0..2 {
+                            // Skip the corners so it's round
+                            if x * z == 0 || y == 0 {
+                                ret.push(Place::new(
+                                    top + IVec3::new(x, y - 1, z),
+                                    Material::LEAF,
+                                    false,
+                                ));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        ret
+    }
+}
+
+/// A lump of stone half buried in the ground
+pub struct Boulder;
+
+impl Feature for Boulder {
+    fn frequency(&self, biome: Biome) -> f64 {
+        match biome {
+            Biome::Mountains => 1.5,
+            Biome::Tundra => 0.5,
+            Biome::Plains => 0.3,
+            _ => 0.1,
+        }
+    }
+
+    fn radius(&self) -> i32 {
+        3
+    }
+
+    fn place(&self, _gen: &Gen, site: Site, rand: &mut Rand) -> Vec<Place> {
+        if site.material == Material::WATER {
+            return Vec::new();
+        }
+        let r = 1.2 + rand.next() as f32 * 1.6;
+        // Squash it a bit so it isn't a perfect sphere
+        let squash = 1.0 + rand.next() as f32 * 0.5;
+        let mut ret = Vec::new();
+        for x in -3..=3 {
+            for y in -3..=3 {
+                for z in -3..=3 {
+                    let d = Vec3::new(x as f32, y as f32 * squash, z as f32);
+                    if d.norm() <= r {
+                        ret.push(Place::new(
+                            site.pos + IVec3::new(x, y, z),
+                            Material::STONE,
+                            true,
+                        ));
+                    }
+                }
+            }
+        }
+        ret
+    }
+}
+
+/// Crumbling stone walls around a square floor
+pub struct Ruins;
+
+impl Feature for Ruins {
+    fn frequency(&self, biome: Biome) -> f64 {
+        match biome {
+            Biome::Mountains => 0.0,
+            _ => 0.03,
+        }
+    }
+
+    fn radius(&self) -> i32 {
+        5
+    }
+
+    fn place(&self, gen: &Gen, site: Site, rand: &mut Rand) -> Vec<Place> {
+        if site.material == Material::WATER {
+            return Vec::new();
+        }
+        let r = rand.range(2, 5);
+        // Only on flat ground, so it isn't floating
+        let flat = [(-r, -r), (-r, r), (r, -r), (r, r)].iter().all(|&(x, z)| {
+            gen.surface(site.pos.x + x, site.pos.z + z)
+                .is_some_and(|s| (s.pos.y - site.pos.y).abs() <= 1)
+        });
+        if !flat {
+            return Vec::new();
+        }
+
+        let mut ret = Vec::new();
+        for x in -r..=r {
+            for z in -r..=r {
+                let p = site.pos + IVec3::new(x, 0, z);
+                ret.push(Place::new(p, Material::STONE, true));
+                if x.abs() == r || z.abs() == r {
+                    // Some of the wall has fallen down
+                    let h = rand.range(-1, 4);
+                    for y in 1..=h {
+                        ret.push(Place::new(p + IVec3::new(0, y, 0), Material::STONE, false));
+                    }
+                }
+            }
+        }
+        ret
+    }
+}
+
+/// A pond sunk into the ground
+pub struct Lake;
+
+impl Feature for Lake {
+    fn frequency(&self, biome: Biome) -> f64 {
+        match biome {
+            Biome::Plains | Biome::Forest => 0.15,
+            Biome::Tundra => 0.05,
+            _ => 0.0,
+        }
+    }
+
+    fn radius(&self) -> i32 {
+        6
+    }
+
+    fn place(&self, gen: &Gen, site: Site, rand: &mut Rand) -> Vec<Place> {
+        if !fertile(site) {
+            return Vec::new();
+        }
+        let r = rand.range(3, 7);
+        // The rim has to be at least as high as the water, or it would spill out
+        let rim = (0..8).all(|i| {
+            let a = i as f32 * std::f32::consts::PI / 4.0;
+            let x = site.pos.x + (a.cos() * r as f32).round() as i32;
+            let z = site.pos.z + (a.sin() * r as f32).round() as i32;
+            gen.surface(x, z).is_some_and(|s| {
+                s.material != Material::WATER && s.pos.y >= site.pos.y && s.pos.y <= site.pos.y + 2
+            })
+        });
+        if !rim {
+            return Vec::new();
+        }
+
+        let mut ret = Vec::new();
+        for x in -r..=r {
+            for z in -r..=r {
+                let d2 = (x * x + z * z) as f32 / (r * r) as f32;
+                if d2 >= 1.0 {
+                    continue;
+                }
+                let depth = ((1.0 - d2) * r as f32 / 2.0).ceil() as i32;
+                let p = site.pos + IVec3::new(x, 0, z);
+                for y in 1 - depth..=0 {
+                    ret.push(Place::new(p + IVec3::new(0, y, 0), Material::WATER, true));
+                }
+                for y in 1..=3 {
+                    ret.push(Place::new(p + IVec3::new(0, y, 0), Material::AIR, true));
+                }
+            }
+        }
+        ret
+    }
+}
@@ -5,6 +5,7 @@ out vec4 frag_color;
 in vec3 frag_pos;
 in vec3 normal;
 flat in uint mat_index;
+flat in uint light_level;
 
 uint encode_normal(vec3 n) {
     uint ret = 0u;
@@ -27,6 +28,7 @@ vec3 decode_normal(uint n) {
 }
 
 void main() {
-    uint w = (mat_index << 3u) | encode_normal(normal);
+    // 3 bits of normal, 16 of material, then 8 of light
+    uint w = (light_level << 19u) | (mat_index << 3u) | encode_normal(normal);
     frag_color = vec4(frag_pos, uintBitsToFloat(w));
 }
@@ -3,9 +3,11 @@
 in vec3 pos;
 in vec3 nor;
 in uint mat;
+in uint light;
 out vec3 normal;
 out vec3 frag_pos;
 flat out uint mat_index;
+flat out uint light_level;
 
 uniform mat4 proj_mat;
 uniform mat4 model;
@@ -16,4 +18,5 @@ void main() {
     frag_pos = p.xyz / p.w;
     normal = nor;
     mat_index = mat;
+    light_level = light;
 }
@@ -0,0 +1,220 @@
+// The kinds of worlds there are, and the trait the chunk thread uses to generate them.
+// The normal terrain generator is `terrain::Gen`; the rest are simple ones for building and testing.
+
+use crate::common::*;
+use crate::terrain::Gen;
+use crate::world::WorldMeta;
+use serde::{Deserialize, Serialize};
+
+/// Makes the chunks for a world, one `pipeline` stage at a time.
+/// Everything it makes should only depend on the seed and its settings,
+/// so the same world always comes out the same no matter what order chunks are generated in.
+pub trait WorldGenerator: Send + Sync {
+    /// The blocks in chunk `pos`, before caves are carved out
+    fn gen(&self, pos: IVec3) -> Chunk;
+
+    /// Carves the caves out of chunk `pos`, which came from `gen`
+    fn carve(&self, chunk: Chunk, _pos: IVec3) -> Chunk {
+        chunk
+    }
+
+    /// Adds features to chunk `pos`, which has been carved
+    fn decorate(&self, _chunk: &mut Chunk, _pos: IVec3) {}
+}
+
+/// The blocks in chunk `pos`, doing the stages that place them all at once, for tests that don't need a `Pipeline`
+#[cfg(test)]
+pub fn generate(gen: &dyn WorldGenerator, pos: IVec3) -> Chunk {
+    let mut chunk = gen.carve(gen.gen(pos), pos);
+    gen.decorate(&mut chunk, pos);
+    chunk
+}
+
+/// Which generator a world uses, which is saved with the world
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub enum WorldType {
+    /// The normal terrain, using the world's `GenConfig`
+    #[default]
+    Normal,
+    /// Flat layers of materials, from the top down, with the top at y = 0.
+    /// The last layer goes down forever.
+    Flat(Vec<Layer>),
+    /// Nothing at all
+    Void,
+    /// Every material in a grid, with gaps between them, for seeing what they look like
+    Checkerboard,
+}
+
+impl WorldType {
+    /// The world type called `name` on the command line, with its default settings
+    pub fn named(name: &str) -> Option<Self> {
+        match name {
+            "normal" => Some(WorldType::Normal),
+            "flat" => Some(WorldType::Flat(default_layers())),
+            "void" => Some(WorldType::Void),
+            "checkerboard" => Some(WorldType::Checkerboard),
+            _ => None,
+        }
+    }
+}
+
+/// One layer of a flat world
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Layer {
+    /// The name of the material, from the registry
+    pub material: String,
+    /// How many blocks thick it is
+    pub depth: u32,
+}
+
+pub fn default_layers() -> Vec<Layer> {
+    let layer = |material: &str, depth| Layer {
+        material: material.to_string(),
+        depth,
+    };
+    vec![layer("grass", 1), layer("dirt", 3), layer("stone", 1)]
+}
+
+/// The generator for the world `meta` is from.
+/// This looks up materials by name, so the registry has to be set first.
+pub fn generator(meta: &WorldMeta) -> Box<dyn WorldGenerator> {
+    let gen: Box<dyn WorldGenerator> = match &meta.world_type {
+        WorldType::Normal => Box::new(Gen::new(meta.seed, meta.gen.clone())),
+        WorldType::Flat(layers) => Box::new(Flat::new(layers)),
+        WorldType::Void => Box::new(Void),
+        WorldType::Checkerboard => Box::new(Checkerboard::new()),
+    };
+    match meta.border {
+        Some(border) => Box::new(Bordered { gen, border }),
+        None => gen,
+    }
+}
+
+/// Another generator, but only inside the world border; everything outside is empty
+struct Bordered {
+    gen: Box<dyn WorldGenerator>,
+    border: Border,
+}
+
+impl WorldGenerator for Bordered {
+    fn gen(&self, pos: IVec3) -> Chunk {
+        if self.border.contains(pos) {
+            self.gen.gen(pos)
+        } else {
+            Chunk::empty()
+        }
+    }
+
+    fn carve(&self, chunk: Chunk, pos: IVec3) -> Chunk {
+        if self.border.contains(pos) {
+            self.gen.carve(chunk, pos)
+        } else {
+            chunk
+        }
+    }
+
+    fn decorate(&self, chunk: &mut Chunk, pos: IVec3) {
+        if self.border.contains(pos) {
+            self.gen.decorate(chunk, pos)
+        }
+    }
+}
+
+struct Flat {
+    /// Each layer's material, and the y of the block right under it
+    layers: Vec<(Material, i32)>,
+}
+
+impl Flat {
+    fn new(layers: &[Layer]) -> Self {
+        let mut bottom = 0;
+        Flat {
+            layers: layers
+                .iter()
+                .filter_map(|l| match Material::named(&l.material) {
+                    Some(m) => {
+                        bottom -= l.depth as i32;
+                        Some((m, bottom - 1))
+                    }
+                    None => {
+                        println!("Skipping layer {:?}, since it isn't a material", l.material);
+                        None
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    fn block(&self, y: i32) -> Material {
+        if y >= 0 {
+            return Material::AIR;
+        }
+        self.layers
+            .iter()
+            .find(|(_, under)| y > *under)
+            .or_else(|| self.layers.last())
+            .map_or(Material::AIR, |(m, _)| *m)
+    }
+}
+
+impl WorldGenerator for Flat {
+    fn gen(&self, pos: IVec3) -> Chunk {
+        let start = pos.y * CHUNK_SIZE as i32;
+        if start >= 0 {
+            return Chunk::empty();
+        }
+        Chunk::full(&|p| self.block(start + p.y as i32))
+    }
+}
+
+struct Void;
+
+impl WorldGenerator for Void {
+    fn gen(&self, _pos: IVec3) -> Chunk {
+        Chunk::empty()
+    }
+}
+
+struct Checkerboard {
+    materials: Vec<Material>,
+    /// How many materials are in each row of the grid
+    side: i32,
+}
+
+impl Checkerboard {
+    fn new() -> Self {
+        let materials: Vec<Material> = registry()
+            .defs()
+            .into_iter()
+            .map(|d| Material(d.id))
+            .filter(|&m| m != Material::AIR)
+            .collect();
+        let side = (materials.len() as f64).sqrt().ceil() as i32;
+        Checkerboard { materials, side }
+    }
+
+    /// The material at `(x, 0, z)`, which is on every other block in both directions, starting at the origin
+    fn block(&self, x: i32, z: i32) -> Material {
+        if x < 0 || z < 0 || x % 2 != 0 || z % 2 != 0 || x / 2 >= self.side {
+            return Material::AIR;
+        }
+        let i = (z / 2 * self.side + x / 2) as usize;
+        self.materials.get(i).cloned().unwrap_or(Material::AIR)
+    }
+}
+
+impl WorldGenerator for Checkerboard {
+    fn gen(&self, pos: IVec3) -> Chunk {
+        if pos.y != 0 {
+            return Chunk::empty();
+        }
+        let start = pos.map(|x| x * CHUNK_SIZE as i32);
+        Chunk::full(&|p| {
+            if p.y == 0 {
+                self.block(start.x + p.x as i32, start.z + p.z as i32)
+            } else {
+                Material::AIR
+            }
+        })
+    }
+}
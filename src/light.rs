@@ -0,0 +1,601 @@
+// Voxel lighting, which the client works out for itself as chunks come in and blocks change,
+// and the pipeline works out for new chunks from the chunks around them.
+// There are two kinds of light, sky light and light from blocks like lamps, which each go from 0 to 15.
+// Light gets dimmer by one for each block it spreads through, except sky light going straight down through air.
+// It doesn't go into opaque blocks, so those are always dark, and faces use the light in front of them.
+
+use crate::common::*;
+use crate::mesh::chunks_touching;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, RwLock};
+
+pub const MAX_LIGHT: u8 = 15;
+
+/// Both kinds of light are packed into one byte per block, with sky light in the high four bits
+#[derive(Clone, Copy, PartialEq)]
+enum Channel {
+    Sky,
+    Block,
+}
+
+const CHANNELS: [Channel; 2] = [Channel::Sky, Channel::Block];
+
+impl Channel {
+    fn get(self, light: u8) -> u8 {
+        match self {
+            Channel::Sky => light >> 4,
+            Channel::Block => light & 0xF,
+        }
+    }
+
+    /// `light` with this channel set to `level`
+    fn with(self, light: u8, level: u8) -> u8 {
+        match self {
+            Channel::Sky => (light & 0xF) | (level << 4),
+            Channel::Block => (light & 0xF0) | level,
+        }
+    }
+}
+
+fn adjacent(p: IVec3) -> [IVec3; 6] {
+    [
+        p - IVec3::x(),
+        p + IVec3::x(),
+        p - IVec3::y(),
+        p + IVec3::y(),
+        p - IVec3::z(),
+        p + IVec3::z(),
+    ]
+}
+
+/// How bright light at `level` is after it spreads into a block of `to`; `down` is whether it's going straight down
+fn spread_into(registry: &Registry, ch: Channel, level: u8, down: bool, to: Material) -> u8 {
+    dim(
+        ch,
+        level,
+        down,
+        registry.get(to).transparent(),
+        to == Material::AIR,
+    )
+}
+
+/// `spread_into` for when we already know whether the block is transparent, and whether it's air
+fn dim(ch: Channel, level: u8, down: bool, transparent: bool, air: bool) -> u8 {
+    if !transparent {
+        0
+    } else if ch == Channel::Sky && down && level == MAX_LIGHT && air {
+        MAX_LIGHT
+    } else {
+        level.saturating_sub(1)
+    }
+}
+
+/// How much light a block of `m` makes itself.
+/// `open_above` is whether the chunk above it isn't loaded, in which case we guess it's open sky.
+fn source(registry: &Registry, ch: Channel, m: Material, open_above: bool) -> u8 {
+    match ch {
+        Channel::Block => registry.get(m).light.min(MAX_LIGHT),
+        Channel::Sky if open_above => spread_into(registry, ch, MAX_LIGHT, true, m),
+        Channel::Sky => 0,
+    }
+}
+
+/// Spreads light around the loaded chunks one block at a time, keeping track of which chunks it changed
+struct Lighter<'a> {
+    chunks: &'a HashMap<IVec3, Arc<RwLock<Chunk>>>,
+    registry: Arc<Registry>,
+    changed: HashSet<IVec3>,
+}
+
+impl<'a> Lighter<'a> {
+    fn new(chunks: &'a HashMap<IVec3, Arc<RwLock<Chunk>>>) -> Self {
+        Lighter {
+            chunks,
+            registry: registry(),
+            changed: HashSet::new(),
+        }
+    }
+
+    /// The chunks whose meshes need updating
+    fn finish(self) -> HashSet<IVec3> {
+        let chunks = self.chunks;
+        self.changed
+            .into_iter()
+            .filter(|c| chunks.contains_key(c))
+            .collect()
+    }
+
+    /// The block at `block` and its light, if it's loaded
+    fn get(&self, block: IVec3) -> Option<(Material, u8)> {
+        let (chunk, idx) = split_block(block);
+        let chunk = self.chunks.get(&chunk)?.read().unwrap();
+        Some((chunk.block(idx), chunk.light(idx)))
+    }
+
+    fn set(&mut self, block: IVec3, ch: Channel, level: u8) {
+        let (chunk, idx) = split_block(block);
+        if let Some(chunk) = self.chunks.get(&chunk) {
+            let mut chunk = chunk.write().unwrap();
+            let light = chunk.light(idx);
+            chunk.set_light(idx, ch.with(light, level));
+        }
+        self.changed.extend(chunks_touching(block));
+    }
+
+    fn source(&self, block: IVec3, m: Material, ch: Channel) -> u8 {
+        let (above, _) = split_block(block + IVec3::y());
+        source(&self.registry, ch, m, !self.chunks.contains_key(&above))
+    }
+
+    /// Spreads the light in the blocks in `queue` as far as it goes
+    fn spread(&mut self, ch: Channel, mut queue: VecDeque<IVec3>) {
+        while let Some(p) = queue.pop_front() {
+            let level = match self.get(p) {
+                Some((_, light)) => ch.get(light),
+                None => continue,
+            };
+            if level == 0 {
+                continue;
+            }
+            for &n in &adjacent(p) {
+                if let Some((m, light)) = self.get(n) {
+                    let new = spread_into(&self.registry, ch, level, n.y < p.y, m);
+                    if new > ch.get(light) {
+                        self.set(n, ch, new);
+                        queue.push_back(n);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Takes away the light that came from the blocks in `queue`, which have already gone dark, along with what their level was.
+    /// Returns the blocks around the dark area that still have light, which needs to spread back into it.
+    fn unspread(&mut self, ch: Channel, mut queue: VecDeque<(IVec3, u8)>) -> VecDeque<IVec3> {
+        let mut again = VecDeque::new();
+        while let Some((p, level)) = queue.pop_front() {
+            for &n in &adjacent(p) {
+                let (m, light) = match self.get(n) {
+                    Some(x) => x,
+                    None => continue,
+                };
+                let light = ch.get(light);
+                if light == 0 {
+                    continue;
+                }
+                if light <= spread_into(&self.registry, ch, level, n.y < p.y, m) {
+                    // Its light came from `p`, unless it makes its own
+                    let s = self.source(n, m, ch);
+                    self.set(n, ch, s);
+                    if s > 0 {
+                        again.push_back(n);
+                    }
+                    queue.push_back((n, light));
+                } else {
+                    again.push_back(n);
+                }
+            }
+        }
+        again
+    }
+}
+
+/// Updates the light around `block` after it changed.
+/// Returns the chunks whose meshes need updating.
+pub fn relight(chunks: &HashMap<IVec3, Arc<RwLock<Chunk>>>, block: IVec3) -> HashSet<IVec3> {
+    let mut l = Lighter::new(chunks);
+    let (m, light) = match l.get(block) {
+        Some(x) => x,
+        None => return HashSet::new(),
+    };
+    for &ch in &CHANNELS {
+        l.set(block, ch, 0);
+        let mut again = l.unspread(ch, vec![(block, ch.get(light))].into());
+        let s = l.source(block, m, ch);
+        if s > 0 {
+            l.set(block, ch, s);
+            again.push_back(block);
+        }
+        // Light from around it can come in now, if it's transparent
+        again.extend(adjacent(block).iter());
+        l.spread(ch, again);
+    }
+    l.finish()
+}
+
+const SIZE: i32 = CHUNK_SIZE as i32;
+const PADDED: i32 = SIZE + 2;
+
+/// The index of `p`, relative to the corner of a chunk, in the chunk plus a layer of blocks around it
+fn padded(p: IVec3) -> usize {
+    (((p.y + 1) * PADDED + p.x + 1) * PADDED + p.z + 1) as usize
+}
+
+/// Lights chunk `pos`, which was just loaded, and spreads light between it and its neighbors.
+/// Returns the chunks whose meshes need updating.
+pub fn light_chunk(chunks: &HashMap<IVec3, Arc<RwLock<Chunk>>>, pos: IVec3) -> HashSet<IVec3> {
+    let mut l = Lighter::new(chunks);
+    let start = pos.map(|x| x * SIZE);
+
+    // Most of the work is inside the chunk, so we do that here instead of one block at a time with `Lighter`
+    let volume = (PADDED * PADDED * PADDED) as usize;
+    let mut mats = vec![None; volume];
+    let mut lights = vec![0; volume];
+    match chunks.get(&pos) {
+        Some(chunk) => {
+            let chunk = chunk.read().unwrap();
+            for y in 0..SIZE {
+                for x in 0..SIZE {
+                    for z in 0..SIZE {
+                        let p = IVec3::new(x, y, z);
+                        mats[padded(p)] = Some(chunk.block(p.map(|x| x as usize)));
+                    }
+                }
+            }
+        }
+        None => return HashSet::new(),
+    }
+
+    // The blocks next to each side of the chunk
+    let mut border = Vec::new();
+    for a in 0..3 {
+        for &side in &[-1, SIZE] {
+            for u in 0..SIZE {
+                for v in 0..SIZE {
+                    let mut p = IVec3::zeros();
+                    p[a] = side;
+                    p[(a + 1) % 3] = u;
+                    p[(a + 2) % 3] = v;
+                    border.push(p);
+                }
+            }
+        }
+    }
+    for &p in &border {
+        match l.get(start + p) {
+            Some((m, light)) => {
+                mats[padded(p)] = Some(m);
+                lights[padded(p)] = light;
+            }
+            // We guess it's open sky if the chunk above isn't loaded
+            None if p.y == SIZE => {
+                mats[padded(p)] = Some(Material::AIR);
+                lights[padded(p)] = Channel::Sky.with(0, MAX_LIGHT);
+            }
+            None => (),
+        }
+    }
+
+    // Whether light gets into each block inside the chunk, and whether it's air.
+    // The blocks around the chunk count as opaque here, since light going out of the chunk is handled later.
+    let mut transparent = vec![false; volume];
+    let mut air = vec![false; volume];
+    for y in 0..SIZE {
+        for x in 0..SIZE {
+            for z in 0..SIZE {
+                let i = padded(IVec3::new(x, y, z));
+                let m = mats[i].unwrap();
+                transparent[i] = l.registry.get(m).transparent();
+                air[i] = m == Material::AIR;
+            }
+        }
+    }
+    // Going from one index to the next in each direction, along with whether it's straight down
+    let steps = [
+        (-PADDED as isize, false),
+        (PADDED as isize, false),
+        (-(PADDED * PADDED) as isize, true),
+        ((PADDED * PADDED) as isize, false),
+        (-1, false),
+        (1, false),
+    ];
+
+    for &ch in &CHANNELS {
+        let mut queue = VecDeque::new();
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                for z in 0..SIZE {
+                    let i = padded(IVec3::new(x, y, z));
+                    let s = source(&l.registry, ch, mats[i].unwrap(), false);
+                    if s > 0 {
+                        lights[i] = ch.with(lights[i], s);
+                        queue.push_back(i);
+                    }
+                }
+            }
+        }
+        for &p in &border {
+            // The block inside the chunk next to this one
+            let q = p.map(|x| x.clamp(0, SIZE - 1));
+            let (i, j) = (padded(p), padded(q));
+            let new = dim(ch, ch.get(lights[i]), q.y < p.y, transparent[j], air[j]);
+            if new > ch.get(lights[j]) {
+                lights[j] = ch.with(lights[j], new);
+                queue.push_back(j);
+            }
+        }
+
+        while let Some(i) = queue.pop_front() {
+            let level = ch.get(lights[i]);
+            for &(step, down) in &steps {
+                // Everything in the queue is inside the chunk, so this is at most on the layer around it
+                let n = (i as isize + step) as usize;
+                let new = dim(ch, level, down, transparent[n], air[n]);
+                if new > ch.get(lights[n]) {
+                    lights[n] = ch.with(lights[n], new);
+                    queue.push_back(n);
+                }
+            }
+        }
+    }
+
+    let mut ordered = Vec::with_capacity((SIZE * SIZE * SIZE) as usize);
+    for y in 0..SIZE {
+        for x in 0..SIZE {
+            for z in 0..SIZE {
+                ordered.push(lights[padded(IVec3::new(x, y, z))]);
+            }
+        }
+    }
+    chunks[&pos].write().unwrap().set_lights(ordered);
+
+    // Now light can spread out of the chunk into its neighbors
+    let above_loaded = chunks.contains_key(&(pos + IVec3::y()));
+    for &ch in &CHANNELS {
+        // The chunk below got sky light from above when this one wasn't loaded, which might not be right anymore
+        let mut dark = VecDeque::new();
+        if ch == Channel::Sky {
+            for &p in border.iter().filter(|p| p.y == -1) {
+                let above = start + p + IVec3::y();
+                if let (Some((m, light)), Some((_, above_light))) = (l.get(start + p), l.get(above))
+                {
+                    let light = ch.get(light);
+                    let guess = source(&l.registry, ch, m, true);
+                    let from_above = spread_into(&l.registry, ch, ch.get(above_light), true, m);
+                    if light > 0 && light == guess && from_above < light {
+                        l.set(start + p, ch, 0);
+                        dark.push_back((start + p, light));
+                    }
+                }
+            }
+        }
+        // If nothing went dark, what we worked out above is still right, so we don't need to look it up again
+        let fresh = dark.is_empty();
+        let mut queue = l.unspread(ch, dark);
+
+        for &p in &border {
+            // The block inside the chunk next to this one
+            let q = p.map(|x| x.clamp(0, SIZE - 1));
+            let found = if fresh {
+                match mats[padded(p)] {
+                    Some(m) if p.y != SIZE || above_loaded => {
+                        Some((m, lights[padded(p)], lights[padded(q)]))
+                    }
+                    _ => None,
+                }
+            } else {
+                match (l.get(start + p), l.get(start + q)) {
+                    (Some((m, light)), Some((_, inside))) => Some((m, light, inside)),
+                    _ => None,
+                }
+            };
+            if let Some((m, light, inside)) = found {
+                let new = spread_into(&l.registry, ch, ch.get(inside), p.y < q.y, m);
+                if new > ch.get(light) {
+                    l.set(start + p, ch, new);
+                    queue.push_back(start + p);
+                }
+            }
+        }
+        l.spread(ch, queue);
+    }
+
+    l.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Once;
+
+    type Chunks = HashMap<IVec3, Arc<RwLock<Chunk>>>;
+
+    /// A block that gives off light at level 12, which is registered along with the built-in materials
+    fn lamp() -> Material {
+        static REGISTER: Once = Once::new();
+        REGISTER.call_once(|| {
+            let mut defs = registry().defs();
+            defs.push(MaterialDef {
+                name: "lamp".to_string(),
+                id: 100,
+                light: 12,
+                ..registry().get(Material::STONE).clone()
+            });
+            set_registry(Registry::new(defs));
+        });
+        Material(100)
+    }
+
+    fn v(x: i32, y: i32, z: i32) -> IVec3 {
+        IVec3::new(x, y, z)
+    }
+
+    /// Adds chunk `pos` and lights it, like the client does when a chunk comes in
+    fn load(chunks: &mut Chunks, pos: IVec3, chunk: Chunk) {
+        chunks.insert(pos, Arc::new(RwLock::new(chunk)));
+        light_chunk(chunks, pos);
+    }
+
+    /// Sets a block and relights around it, like the client does when a block changes
+    fn set(chunks: &Chunks, block: IVec3, m: Material) {
+        let (chunk, idx) = split_block(block);
+        chunks[&chunk].write().unwrap().set_block(idx, m);
+        relight(chunks, block);
+    }
+
+    fn sky(chunks: &Chunks, block: IVec3) -> u8 {
+        let (chunk, idx) = split_block(block);
+        Channel::Sky.get(chunks[&chunk].read().unwrap().light(idx))
+    }
+
+    fn lamp_light(chunks: &Chunks, block: IVec3) -> u8 {
+        let (chunk, idx) = split_block(block);
+        Channel::Block.get(chunks[&chunk].read().unwrap().light(idx))
+    }
+
+    /// Every block's light in every chunk, to compare
+    fn lights(chunks: &Chunks) -> HashMap<IVec3, Vec<u8>> {
+        chunks
+            .iter()
+            .map(|(&pos, chunk)| {
+                let chunk = chunk.read().unwrap();
+                let lights = (0..SIZE as usize)
+                    .flat_map(|x| {
+                        (0..SIZE as usize)
+                            .flat_map(move |y| (0..SIZE as usize).map(move |z| UVec3::new(x, y, z)))
+                    })
+                    .map(|idx| chunk.light(idx))
+                    .collect();
+                (pos, lights)
+            })
+            .collect()
+    }
+
+    /// Chunks 0 to 1 along each axis, with stone, lamps and a floor with holes in it
+    fn blocks(pos: IVec3) -> Chunk {
+        let lamp = lamp();
+        Chunk::full(&|p| {
+            let b = pos * SIZE + p.map(|x| x as i32);
+            let mut rand = Rand::new(3, &[b.x as i64, b.y as i64, b.z as i64]);
+            let r = rand.next();
+            if b.y == 40 && (b.x % 7 != 0 || b.z % 5 != 0) {
+                Material::STONE
+            } else if r < 0.002 {
+                lamp
+            } else if r < 0.2 {
+                Material::STONE
+            } else {
+                Material::AIR
+            }
+        })
+    }
+
+    fn locs() -> Vec<IVec3> {
+        (0..8).map(|i| v(i & 1, (i >> 1) & 1, i >> 2)).collect()
+    }
+
+    /// `blocks`, loaded one at a time in `order`
+    fn world(order: &[IVec3]) -> Chunks {
+        let mut chunks = HashMap::new();
+        for &pos in order {
+            load(&mut chunks, pos, blocks(pos));
+        }
+        chunks
+    }
+
+    #[test]
+    fn overhang() {
+        let mut chunks = HashMap::new();
+        let mut chunk = Chunk::empty();
+        for x in 0..10 {
+            for z in 0..10 {
+                chunk.set_block(UVec3::new(x, 20, z), Material::STONE);
+            }
+        }
+        // There's nothing above it, so it's open sky
+        load(&mut chunks, v(0, 0, 0), chunk);
+
+        assert_eq!(sky(&chunks, v(5, 21, 5)), MAX_LIGHT);
+        assert_eq!(sky(&chunks, v(5, 20, 5)), 0);
+        assert_eq!(sky(&chunks, v(15, 0, 15)), MAX_LIGHT);
+        // Under it, light comes in from the nearest edge and gets dimmer on the way
+        assert_eq!(sky(&chunks, v(5, 19, 5)), MAX_LIGHT - 5);
+        assert_eq!(sky(&chunks, v(5, 0, 5)), MAX_LIGHT - 5);
+        assert_eq!(sky(&chunks, v(0, 19, 0)), MAX_LIGHT - 10);
+        assert_eq!(sky(&chunks, v(9, 19, 9)), MAX_LIGHT - 1);
+    }
+
+    #[test]
+    fn across_border() {
+        let lamp = lamp();
+        for &lamp_first in &[true, false] {
+            let mut chunks = HashMap::new();
+            let mut chunk = Chunk::empty();
+            chunk.set_block(UVec3::new(29, 5, 5), lamp);
+            if lamp_first {
+                load(&mut chunks, v(0, 0, 0), chunk);
+                load(&mut chunks, v(1, 0, 0), Chunk::empty());
+            } else {
+                load(&mut chunks, v(1, 0, 0), Chunk::empty());
+                load(&mut chunks, v(0, 0, 0), chunk);
+            }
+
+            // It goes 3 blocks to the border at x = 32, and 9 more after it
+            for k in 0..16 {
+                assert_eq!(
+                    lamp_light(&chunks, v(29 + k, 5, 5)),
+                    12u8.saturating_sub(k as u8),
+                    "{} blocks away",
+                    k
+                );
+            }
+            assert_eq!(lamp_light(&chunks, v(33, 6, 7)), 12 - 7);
+        }
+    }
+
+    #[test]
+    fn any_order() {
+        let locs = locs();
+        let expected = lights(&world(&locs));
+
+        let mut reversed = locs.clone();
+        reversed.reverse();
+        assert!(lights(&world(&reversed)) == expected);
+
+        let mut shuffled = locs.clone();
+        shuffled.rotate_left(3);
+        shuffled.swap(0, 5);
+        assert!(lights(&world(&shuffled)) == expected);
+
+        // All at once, which is how the client adds the chunks that come in together
+        let chunks: Chunks = locs
+            .iter()
+            .map(|&p| (p, Arc::new(RwLock::new(blocks(p)))))
+            .collect();
+        for &p in &locs {
+            light_chunk(&chunks, p);
+        }
+        assert!(lights(&chunks) == expected);
+    }
+
+    #[test]
+    fn relight_matches() {
+        let lamp = lamp();
+        let chunks = world(&locs());
+        // Somewhere open under the floor, right next to the border between chunks on two axes
+        let at = v(31, 39, 32);
+        for &m in &[
+            Material::AIR,
+            Material::STONE,
+            Material::AIR,
+            lamp,
+            Material::AIR,
+        ] {
+            set(&chunks, at, m);
+            set(&chunks, at + v(0, 2, 0), m);
+
+            let fresh: Chunks = chunks
+                .iter()
+                .map(|(&p, c)| {
+                    let c = c.read().unwrap();
+                    let blocks = Chunk::full(&|idx| c.block(idx));
+                    (p, Arc::new(RwLock::new(blocks)))
+                })
+                .collect();
+            for &p in &locs() {
+                light_chunk(&fresh, p);
+            }
+            assert!(lights(&chunks) == lights(&fresh), "after setting {:?}", m);
+        }
+    }
+}
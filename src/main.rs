@@ -1,6 +1,8 @@
 #[macro_use]
 extern crate glium;
 extern crate glsl_include;
+#[macro_use]
+extern crate lazy_static;
 pub extern crate nalgebra;
 extern crate num_derive;
 extern crate num_traits;
@@ -8,16 +10,26 @@ extern crate num_traits;
 use glium::glutin;
 use std::sync::Arc;
 
+#[cfg(test)]
+mod bench;
+mod biome;
+mod cave;
 mod chunk;
 mod chunk_thread;
 mod client;
 mod client_aux;
 mod common;
 mod config;
+mod feature;
+mod generator;
 mod input;
+mod light;
 mod material;
 mod mesh;
+mod ore;
 mod physics;
+mod pipeline;
+mod region;
 mod server;
 mod terrain;
 mod world;
@@ -34,7 +46,143 @@ pub const APP_INFO: app_dirs2::AppInfo = app_dirs2::AppInfo {
     author: "Lorxu",
 };
 
+/// Loads `name` from the config directory, or writes out the default if it's not there
+fn load_config<T: serde::Serialize + serde::de::DeserializeOwned + Default>(name: &str) -> T {
+    let mut config_file =
+        app_dirs2::app_root(app_dirs2::AppDataType::UserConfig, &APP_INFO).unwrap();
+    config_file.push(name);
+    if config_file.exists() {
+        ron::de::from_reader(File::open(config_file).unwrap()).expect("bad config file")
+    } else {
+        let c = T::default();
+        let s = ron::ser::to_string(&c).unwrap();
+        let mut f = File::create(config_file).unwrap();
+        writeln!(f, "{}", s).unwrap();
+        c
+    }
+}
+
+/// Command line options
+struct Args {
+    /// `--server [addr]`: run a dedicated server
+    server: Option<String>,
+    /// `--connect [addr]`: play on a remote server
+    connect: Option<String>,
+    /// `--world <name>`: the world to play in, instead of the one in the config file
+    world: Option<String>,
+    /// `--world-type <normal|flat|void|checkerboard>`: what kind of world to make, if it doesn't exist yet
+    world_type: Option<generator::WorldType>,
+    /// `--pregen <radius|XxYxZ>`: generate and save the chunks in a radius or box around spawn, and then quit
+    pregen: Option<Area>,
+    /// `--pregen-at <x,y,z>`: pregenerate around this point instead of spawn
+    pregen_at: Option<Vec3>,
+}
+
+/// Takes the next argument if it's an address, since they're optional
+fn optional_addr(
+    args: &mut std::iter::Peekable<impl Iterator<Item = String>>,
+    host: &str,
+) -> String {
+    match args.peek() {
+        Some(x) if !x.starts_with("--") => args.next().unwrap(),
+        _ => format!("{}:{}", host, DEFAULT_PORT),
+    }
+}
+
+fn parse_args() -> Args {
+    let mut args = std::env::args().skip(1).peekable();
+    let mut ret = Args {
+        server: None,
+        connect: None,
+        world: None,
+        world_type: None,
+        pregen: None,
+        pregen_at: None,
+    };
+    while let Some(a) = args.next() {
+        match &a[..] {
+            "--server" => ret.server = Some(optional_addr(&mut args, "0.0.0.0")),
+            "--connect" => ret.connect = Some(optional_addr(&mut args, "127.0.0.1")),
+            "--world" => ret.world = Some(args.next().expect("--world needs a name")),
+            "--world-type" => {
+                let name = args.next().expect("--world-type needs a type");
+                ret.world_type = Some(
+                    generator::WorldType::named(&name)
+                        .unwrap_or_else(|| panic!("Unknown world type {:?}", name)),
+                );
+            }
+            "--pregen" => {
+                let area = args.next().expect("--pregen needs a radius or box");
+                ret.pregen = Some(
+                    Area::parse(&area)
+                        .unwrap_or_else(|| panic!("{:?} isn't a radius or box", area)),
+                );
+            }
+            "--pregen-at" => {
+                let pos = args.next().expect("--pregen-at needs a position");
+                let n: Vec<f32> = pos
+                    .split(',')
+                    .map(|x| x.parse().ok())
+                    .collect::<Option<_>>()
+                    .filter(|n: &Vec<f32>| n.len() == 3)
+                    .unwrap_or_else(|| panic!("{:?} isn't a position like 0,16,0", pos));
+                ret.pregen_at = Some(Vec3::new(n[0], n[1], n[2]));
+            }
+            x => panic!("Unknown argument {:?}", x),
+        }
+    }
+    ret
+}
+
 fn main() {
+    let args = parse_args();
+    if let Some(area) = args.pregen {
+        load_server(args.world, args.world_type).pregenerate(area, args.pregen_at);
+        return;
+    }
+    match args.server {
+        Some(addr) => server_main(addr, args.world, args.world_type),
+        None => client_main(args.connect, args.world, args.world_type),
+    }
+}
+
+/// Runs a dedicated server, with no window, until someone types `stop`
+fn server_main(addr: String, world: Option<String>, world_type: Option<generator::WorldType>) {
+    let mut server = load_server(world, world_type);
+    server
+        .listen(&addr)
+        .unwrap_or_else(|e| panic!("Couldn't listen on {}: {}", addr, e));
+    println!("Listening on {}", addr);
+    server.read_console();
+    server.run();
+}
+
+/// Creates a server with the dedicated server's config, for `world` if it's given instead of the one in the config file
+fn load_server(world: Option<String>, world_type: Option<generator::WorldType>) -> Server {
+    let mut config = load_config::<GameConfig>("server.ron");
+    if let Some(world) = world {
+        config.world = world;
+    }
+    if let Some(world_type) = world_type {
+        config.world_type = world_type;
+    }
+    let config = Arc::new(config);
+    let materials = load_config::<MaterialConfig>("materials.ron");
+    Server::new(config, materials).unwrap_or_else(|e| quit(&e))
+}
+
+/// Reports a problem with the command line or config and quits, since there's nothing else we can do
+fn quit(e: &str) -> ! {
+    println!("{}", e);
+    std::process::exit(1)
+}
+
+/// Opens a window and plays, either on a remote server at `addr` or on a local one in a background thread
+fn client_main(
+    addr: Option<String>,
+    world: Option<String>,
+    world_type: Option<generator::WorldType>,
+) {
     // Wayland doesn't allow cursor grabbing
     let events_loop: glutin::EventsLoop = glutin::os::unix::EventsLoopExt::new_x11().unwrap();
     let wb = glutin::WindowBuilder::new()
@@ -45,28 +193,17 @@ fn main() {
     display.gl_window().window().grab_cursor(true).unwrap();
     display.gl_window().window().hide_cursor(true);
 
-    let mut config_file =
-        app_dirs2::app_root(app_dirs2::AppDataType::UserConfig, &APP_INFO).unwrap();
-    config_file.push("config.ron");
-    let client_config = if config_file.exists() {
-        ron::de::from_reader(File::open(config_file).unwrap()).expect("bad config file")
-    } else {
-        let c = ClientConfig {
-            mesher: crate::mesh::Mesher::Greedy,
-            wireframe: false,
-            batch_size: 16,
-            keycodes: crate::input::DEFAULT_KEY_CODES,
-            game_config: Arc::new(GameConfig {
-                draw_chunks: 16,
-                batch_size: 64,
-                save_chunks: true,
-            }),
-        };
-        let s = ron::ser::to_string(&c).unwrap();
-        let mut f = File::create(config_file).unwrap();
-        writeln!(f, "{}", s).unwrap();
-        c
-    };
+    let mut client_config = load_config::<ClientConfig>("config.ron");
+    {
+        // Nothing else has a reference to it yet
+        let game_config = Arc::get_mut(&mut client_config.game_config).unwrap();
+        if let Some(world) = world {
+            game_config.world = world;
+        }
+        if let Some(world_type) = world_type {
+            game_config.world_type = world_type;
+        }
+    }
     let client_config = Arc::new(client_config);
 
     let config = Arc::clone(&client_config.game_config);
@@ -78,15 +215,26 @@ fn main() {
         .unwrap()
         .into();
 
-    let camera_pos = Vec3::new(4.0, 16.0, 4.0);
+    let conn = match addr {
+        Some(addr) => Connection::connect(&addr)
+            .unwrap_or_else(|e| panic!("Couldn't connect to {}: {}", addr, e)),
+        None => {
+            let materials = load_config::<MaterialConfig>("materials.ron");
+            let (conn_client, conn_server) = Connection::local();
+            std::thread::spawn(move || {
+                let mut server = Server::new(config, materials).unwrap_or_else(|e| quit(&e));
+                server.connect(conn_server);
+                server.run();
+            });
+            conn_client
+        }
+    };
+    let welcome = conn
+        .login(client_config.name.clone(), None)
+        .unwrap_or_else(|e| panic!("Couldn't join the game: {}", e));
+    println!("Joined as player {} on seed {}", welcome.id, welcome.seed);
 
-    let (conn_client, conn_server) = Connection::local();
-    let client = Client::new(display, Arc::clone(&client_config), conn_client, camera_pos);
-    std::thread::spawn(move || {
-        let mut server = Server::new(config);
-        server.join(conn_server, camera_pos);
-        server.run();
-    });
+    let client = Client::new(display, Arc::clone(&client_config), conn, welcome);
 
     client.game_loop(resolution, events_loop);
 }
@@ -1,18 +1,50 @@
-use enum_iterator::IntoEnumIterator;
-use serde::{Deserialize, Serialize};
-
-#[derive(IntoEnumIterator, PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
-#[repr(u16)]
-pub enum Material {
-    Air = 0,
-    Stone,
-    Grass,
-    Dirt,
-    Water = 4,
-    Sand,
-    Wood,
-    Leaf,
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// A kind of block, which is just its numeric id; everything else about it is in the `Registry`.
+/// Ids are stable within a world, since they're what's saved in chunks.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct Material(pub u16);
+
+// The materials the game itself needs, like for terrain generation, which always have these ids.
+// These are the same as back when `Material` was an enum, so old saves still work.
+impl Material {
+    pub const AIR: Material = Material(0);
+    pub const STONE: Material = Material(1);
+    pub const GRASS: Material = Material(2);
+    pub const DIRT: Material = Material(3);
+    pub const WATER: Material = Material(4);
+    pub const SAND: Material = Material(5);
+    pub const WOOD: Material = Material(6);
+    pub const LEAF: Material = Material(7);
+    pub const SNOW: Material = Material(8);
+    pub const COAL: Material = Material(9);
+    pub const COPPER: Material = Material(10);
+    pub const IRON: Material = Material(11);
+    pub const GOLD: Material = Material(12);
+    pub const DIAMOND: Material = Material(13);
+}
+
+// Enums are saved as a u32 variant index, so we keep doing that for compatibility
+impl Serialize for Material {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(self.0 as u32)
+    }
 }
+
+impl<'de> Deserialize<'de> for Material {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u32::deserialize(deserializer).map(|x| Material(x as u16))
+    }
+}
+
+impl std::fmt::Display for Material {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", registry().get(*self).name)
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct MatData {
     pub color: [f32; 3],
@@ -24,80 +56,324 @@ pub struct MatData {
 }
 implement_uniform_block!(MatData, color, roughness, trans, metal, ior, nothing);
 
-impl Material {
-    pub fn mat_data(self) -> MatData {
-        match self {
-            Material::Stone => MatData {
-                color: [0.4; 3],
-                roughness: 0.2,
-                trans: 0.0,
-                metal: 0.0,
-                ior: 1.45,
-                nothing: 0.0,
-            },
-            Material::Grass => MatData {
-                color: [0.4, 0.7, 0.5],
-                roughness: 0.6,
-                trans: 0.0,
-                metal: 0.0,
-                ior: 1.45,
-                nothing: 0.0,
-            },
-            Material::Dirt => MatData {
-                color: [0.4, 0.3, 0.3],
-                roughness: 0.9,
-                trans: 0.0,
-                metal: 0.0,
-                ior: 1.45,
-                nothing: 0.0,
-            },
-            Material::Sand => MatData {
-                color: [0.9, 0.7, 0.6],
-                roughness: 0.6,
-                trans: 0.0,
-                metal: 0.0,
-                ior: 1.45,
-                nothing: 0.0,
-            },
-            Material::Water => MatData {
-                color: [0.3, 0.4, 0.5],
-                roughness: 0.01,
-                trans: 0.5,
-                metal: 0.0,
-                ior: 1.33,
-                nothing: 0.0,
-            },
-            Material::Air => MatData {
-                color: [0.0; 3],
-                roughness: 1.0,
-                trans: 1.0,
-                metal: 0.0,
-                ior: 1.0,
-                nothing: 0.0,
-            },
-            Material::Wood => MatData {
-                color: [0.1, 0.1, 0.1],
-                roughness: 0.9,
-                trans: 0.0,
-                metal: 0.0,
-                ior: 1.45,
-                nothing: 0.0,
-            },
-            Material::Leaf => MatData {
-                color: [0.1, 0.3, 0.2],
-                roughness: 0.6,
-                trans: 0.0,
-                metal: 0.0,
-                ior: 1.45,
-                nothing: 0.0,
-            },
+/// Everything about a material, which is loaded from `materials.ron`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MaterialDef {
+    pub name: String,
+    /// The id new worlds use for it; existing worlds keep whatever id it had when they first saw it
+    pub id: u16,
+    pub color: [f32; 3],
+    #[serde(default = "default_roughness")]
+    pub roughness: f32,
+    /// Translucency
+    #[serde(default)]
+    pub trans: f32,
+    /// Metalness
+    #[serde(default)]
+    pub metal: f32,
+    #[serde(default = "default_ior")]
+    pub ior: f32,
+    /// Whether players collide with it
+    #[serde(default = "default_true")]
+    pub solid: bool,
+    /// Whether it's drawn in the second, transparent pass, like water.
+    /// You can build in phase 2 blocks, but not break them.
+    #[serde(default)]
+    pub phase2: bool,
+    /// How long it takes to break, in seconds; if it's negative, it can't be broken
+    #[serde(default = "default_hardness")]
+    pub hardness: f32,
+    /// The name of what you get for breaking it. `None` means the block itself, and `"air"` means nothing.
+    #[serde(default)]
+    pub drops: Option<String>,
+    /// How much light it gives off, up to 15
+    #[serde(default)]
+    pub light: u8,
+}
+
+fn default_roughness() -> f32 {
+    0.5
+}
+fn default_ior() -> f32 {
+    1.45
+}
+fn default_true() -> bool {
+    true
+}
+fn default_hardness() -> f32 {
+    0.5
+}
+
+impl MaterialDef {
+    fn new(name: &str, m: Material, color: [f32; 3], roughness: f32, hardness: f32) -> Self {
+        MaterialDef {
+            name: name.to_string(),
+            id: m.0,
+            color,
+            roughness,
+            trans: 0.0,
+            metal: 0.0,
+            ior: default_ior(),
+            solid: true,
+            phase2: false,
+            hardness,
+            drops: None,
+            light: 0,
         }
     }
 
+    /// A stand-in for a material that's in the world but not in `materials.ron` anymore,
+    /// so the blocks stay around until it's added back
+    fn unknown(name: &str, id: u16) -> Self {
+        MaterialDef::new(name, Material(id), [1.0, 0.0, 1.0], 0.5, default_hardness())
+    }
+
+    /// Whether light goes through it
+    pub fn transparent(&self) -> bool {
+        !self.solid || self.trans > 0.0
+    }
+
+    fn mat_data(&self) -> MatData {
+        MatData {
+            color: self.color,
+            roughness: self.roughness,
+            trans: self.trans,
+            metal: self.metal,
+            ior: self.ior,
+            nothing: 0.0,
+        }
+    }
+}
+
+/// The contents of `materials.ron`
+#[derive(Serialize, Deserialize)]
+pub struct MaterialConfig {
+    pub materials: Vec<MaterialDef>,
+}
+
+impl Default for MaterialConfig {
+    fn default() -> Self {
+        let def = MaterialDef::new;
+        let air = MaterialDef {
+            trans: 1.0,
+            ior: 1.0,
+            solid: false,
+            hardness: -1.0,
+            ..def("air", Material::AIR, [0.0; 3], 1.0, 0.0)
+        };
+        let water = MaterialDef {
+            trans: 0.5,
+            ior: 1.33,
+            solid: false,
+            phase2: true,
+            hardness: -1.0,
+            ..def("water", Material::WATER, [0.3, 0.4, 0.5], 0.01, 0.0)
+        };
+        let grass = MaterialDef {
+            drops: Some("dirt".to_string()),
+            ..def("grass", Material::GRASS, [0.4, 0.7, 0.5], 0.6, 0.3)
+        };
+        let snow = MaterialDef {
+            ior: 1.31,
+            ..def("snow", Material::SNOW, [0.9, 0.9, 0.95], 0.7, 0.1)
+        };
+        let metal = |name, m, color, roughness, metal, hardness| MaterialDef {
+            metal,
+            ..def(name, m, color, roughness, hardness)
+        };
+        let diamond = MaterialDef {
+            ior: 2.42,
+            ..def("diamond", Material::DIAMOND, [0.6, 0.9, 0.95], 0.05, 2.0)
+        };
+        MaterialConfig {
+            materials: vec![
+                air,
+                def("stone", Material::STONE, [0.4; 3], 0.2, 0.6),
+                grass,
+                def("dirt", Material::DIRT, [0.4, 0.3, 0.3], 0.9, 0.25),
+                water,
+                def("sand", Material::SAND, [0.9, 0.7, 0.6], 0.6, 0.25),
+                def("wood", Material::WOOD, [0.1, 0.1, 0.1], 0.9, 0.5),
+                def("leaf", Material::LEAF, [0.1, 0.3, 0.2], 0.6, 0.1),
+                snow,
+                def("coal", Material::COAL, [0.15; 3], 0.8, 0.8),
+                metal("copper", Material::COPPER, [0.7, 0.4, 0.25], 0.4, 0.6, 1.0),
+                metal("iron", Material::IRON, [0.6, 0.5, 0.45], 0.4, 0.5, 1.2),
+                metal("gold", Material::GOLD, [0.9, 0.75, 0.3], 0.2, 1.0, 1.2),
+                diamond,
+            ],
+        }
+    }
+}
+
+/// All the materials in the game, by id and by name
+pub struct Registry {
+    /// Indexed by id
+    defs: Vec<Option<MaterialDef>>,
+    names: HashMap<String, Material>,
+    /// What we use for ids that aren't registered
+    missing: MaterialDef,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry::new(MaterialConfig::default().materials)
+    }
+}
+
+impl Registry {
+    /// Makes a registry out of `defs` as they are, like the ones the server sends.
+    /// The built-in materials always keep their ids, and are added if they're missing.
+    pub fn new(defs: Vec<MaterialDef>) -> Self {
+        let mut r = Registry {
+            defs: Vec::new(),
+            names: HashMap::new(),
+            missing: MaterialDef::unknown("unknown", u16::MAX),
+        };
+        let builtin = MaterialConfig::default().materials;
+        for d in defs {
+            if let Some(b) = builtin.iter().find(|b| b.name == d.name && b.id != d.id) {
+                println!(
+                    "Material {:?} is built in, so it has to have id {}, not {}",
+                    d.name, b.id, d.id
+                );
+                r.add(MaterialDef { id: b.id, ..d });
+            } else {
+                r.add(d);
+            }
+        }
+        for b in builtin {
+            if !r.names.contains_key(&b.name) {
+                r.add(b);
+            }
+        }
+        r
+    }
+
+    /// Makes a registry out of `defs` for a world that's already given the ids in `ids` to materials by name.
+    /// Materials keep the ids they have in the world, and new ones get an id the world isn't using yet.
+    /// New ids are added to `ids`, so it should be saved afterwards.
+    pub fn for_world(mut defs: Vec<MaterialDef>, ids: &mut HashMap<String, u16>) -> Self {
+        let builtin: HashMap<String, u16> = MaterialConfig::default()
+            .materials
+            .into_iter()
+            .map(|d| (d.name, d.id))
+            .collect();
+
+        let mut known = Vec::new();
+        for (name, &id) in ids.iter() {
+            if let Some(d) = defs.iter_mut().find(|d| &d.name == name) {
+                d.id = id;
+                known.push(name.clone());
+            } else if !builtin.contains_key(name) {
+                println!(
+                    "Material {:?} isn't registered anymore, but it's in the world",
+                    name
+                );
+                defs.push(MaterialDef::unknown(name, id));
+                known.push(name.clone());
+            }
+        }
+
+        let mut used: Vec<u16> = ids.values().chain(builtin.values()).cloned().collect();
+        for d in defs.iter_mut() {
+            if let Some(&id) = builtin.get(&d.name) {
+                d.id = id;
+            } else if !known.contains(&d.name) {
+                if used.contains(&d.id) {
+                    d.id = (0..u16::MAX).find(|x| !used.contains(x)).unwrap();
+                }
+                used.push(d.id);
+            }
+            ids.insert(d.name.clone(), d.id);
+        }
+
+        Registry::new(defs)
+    }
+
+    fn add(&mut self, d: MaterialDef) {
+        let id = d.id as usize;
+        if self.names.contains_key(&d.name) {
+            println!("Material {:?} is registered twice", d.name);
+            return;
+        }
+        if let Some(Some(old)) = self.defs.get(id) {
+            println!(
+                "Materials {:?} and {:?} both have id {}",
+                old.name, d.name, id
+            );
+            return;
+        }
+        if self.defs.len() <= id {
+            self.defs.resize(id + 1, None);
+        }
+        self.names.insert(d.name.clone(), Material(d.id));
+        self.defs[id] = Some(d);
+    }
+
+    pub fn get(&self, m: Material) -> &MaterialDef {
+        self.defs
+            .get(m.0 as usize)
+            .and_then(|x| x.as_ref())
+            .unwrap_or(&self.missing)
+    }
+
+    pub fn named(&self, name: &str) -> Option<Material> {
+        self.names.get(name).cloned()
+    }
+
+    /// All the registered materials, in order of id
+    pub fn defs(&self) -> Vec<MaterialDef> {
+        self.defs.iter().flatten().cloned().collect()
+    }
+
+    /// The contents of the `mat_buf` uniform buffer, indexed by id
+    pub fn mat_data(&self) -> Vec<MatData> {
+        self.defs
+            .iter()
+            .map(|x| x.as_ref().unwrap_or(&self.missing).mat_data())
+            .collect()
+    }
+}
+
+lazy_static! {
+    static ref REGISTRY: RwLock<Arc<Registry>> = RwLock::new(Arc::new(Registry::default()));
+}
+
+/// The materials in use right now
+pub fn registry() -> Arc<Registry> {
+    Arc::clone(&REGISTRY.read().unwrap())
+}
+
+/// Replaces the materials in use; the server does this when it loads a world, and the client when it joins.
+pub fn set_registry(registry: Registry) {
+    *REGISTRY.write().unwrap() = Arc::new(registry);
+}
+
+impl Material {
+    pub fn named(name: &str) -> Option<Material> {
+        registry().named(name)
+    }
+
     pub fn phase2(self) -> bool {
-        match self {
-            Material::Water => true,
-            _ => false,
+        registry().get(self).phase2
+    }
+
+    pub fn hardness(self) -> f32 {
+        registry().get(self).hardness
+    }
+
+    /// What you get for breaking it, if anything
+    pub fn drops(self) -> Option<Material> {
+        let r = registry();
+        let m = match &r.get(self).drops {
+            Some(name) => r.named(name)?,
+            None => self,
+        };
+        if m == Material::AIR {
+            None
+        } else {
+            Some(m)
         }
     }
 }
@@ -10,18 +10,41 @@ pub struct Vertex {
     pub pos: [f32; 3],
     nor: [f32; 3],
     mat: u32,
+    /// Sky light in the high four bits and block light in the low four, see `light.rs`
+    light: u32,
 }
 
-implement_vertex!(Vertex, pos, nor, mat);
+implement_vertex!(Vertex, pos, nor, mat, light);
 
-pub fn vert(p: Vec3, n: Vec3, m: Material) -> Vertex {
+pub fn vert(p: Vec3, n: Vec3, m: Material, light: u8) -> Vertex {
     Vertex {
         pos: p.into(),
         nor: n.into(),
-        mat: m as u32,
+        mat: m.0 as u32,
+        light: light as u32,
     }
 }
 
+/// The collision shape for a chunk's mesh, leaving out materials that aren't solid
+pub fn collider(verts: &[Vertex]) -> Option<nc::shape::ShapeHandle<f32>> {
+    let registry = registry();
+    let v_physics: Vec<_> = verts
+        .chunks(3)
+        .filter(|t| registry.get(Material(t[0].mat as u16)).solid)
+        .flatten()
+        .map(|x| na::Point3::from(x.pos))
+        .collect();
+    if v_physics.is_empty() {
+        return None;
+    }
+    let i_physics: Vec<_> = (0..v_physics.len() / 3)
+        .map(|x| na::Point3::new(x * 3, x * 3 + 1, x * 3 + 2))
+        .collect();
+    Some(nc::shape::ShapeHandle::new(nc::shape::TriMesh::new(
+        v_physics, i_physics, None,
+    )))
+}
+
 pub struct Mesh {
     empty: bool,
     empty_p2: bool, // For phase 2, where we draw transparent things
@@ -156,6 +179,27 @@ pub fn neighbors(idx: IVec3) -> Vec<IVec3> {
     .collect()
 }
 
+/// The chunks whose meshes depend on the block at `block`: its own chunk, and any neighbors it's on the edge of
+pub fn chunks_touching(block: IVec3) -> Vec<IVec3> {
+    let chunk = world_to_chunk(block.map(|x| x as f32));
+    let in_chunk = in_chunk(block.map(|x| x as f32));
+
+    let mut touching = vec![chunk];
+    for d in 0..3 {
+        if in_chunk[d] == 0 {
+            let mut i = chunk;
+            i[d] -= 1;
+            touching.push(i);
+        }
+        if in_chunk[d] == CHUNK_SIZE as usize - 1 {
+            let mut i = chunk;
+            i[d] += 1;
+            touching.push(i);
+        }
+    }
+    touching
+}
+
 pub fn neighbor_axis(
     neighbors: &[Arc<RwLock<Chunk>>],
     axis: usize,
@@ -193,6 +237,7 @@ impl Mesher {
 
 /// This is just naive meshing with culling of interior faces within a chunk
 fn culled(grid: &Chunk, neighbors: Vec<Arc<RwLock<Chunk>>>, phase2: bool) -> Vec<Vertex> {
+    let registry = registry();
     let mut vertices = Vec::new();
 
     // Sweep on all three axes
@@ -207,19 +252,23 @@ fn culled(grid: &Chunk, neighbors: Vec<Arc<RwLock<Chunk>>>, phase2: bool) -> Vec
         let fb = neighbor_axis(&neighbors, d);
 
         // The faces that need to be drawn
-        let mut culled = grid.cull_faces(d, (&fb.0.read().unwrap(), &fb.1.read().unwrap()), phase2);
+        let fb = (&*fb.0.read().unwrap(), &*fb.1.read().unwrap());
+        let mut culled = grid.cull_faces(d, fb, phase2, &registry);
         if culled.is_empty() {
             continue;
         }
+        let lights = grid.face_lights(d, fb);
 
         // The actual sweeping
         for d_i in 0..=CHUNK_SIZE as usize {
             let culled = &mut culled[d_i];
+            let lights = &lights[d_i];
             // Generate mesh
             for u_i in 0..CHUNK_SIZE as usize {
                 for v_i in 0..CHUNK_SIZE as usize {
                     let b = culled[u_i][v_i];
-                    if b != Material::Air {
+                    let light = lights[u_i][v_i];
+                    if b != Material::AIR {
                         // Add this face to the mesh
                         let left = (u_i, v_i);
                         let right = (u_i + 1, v_i + 1);
@@ -231,28 +280,28 @@ fn culled(grid: &Chunk, neighbors: Vec<Arc<RwLock<Chunk>>>, phase2: bool) -> Vec
                         vleft[d] = d_i as f32;
                         vleft[u] = left.0 as f32;
                         vleft[v] = left.1 as f32;
-                        let vleft = vert(vleft, normal, b);
+                        let vleft = vert(vleft, normal, b, light);
 
                         // Top left
                         let mut vmid = Vec3::zeros();
                         vmid[d] = d_i as f32;
                         vmid[u] = right.0 as f32;
                         vmid[v] = left.1 as f32;
-                        let vmid = vert(vmid, normal, b);
+                        let vmid = vert(vmid, normal, b, light);
 
                         // Top right
                         let mut vright = Vec3::zeros();
                         vright[d] = d_i as f32;
                         vright[u] = right.0 as f32;
                         vright[v] = right.1 as f32;
-                        let vright = vert(vright, normal, b);
+                        let vright = vert(vright, normal, b, light);
 
                         // Bottom right
                         let mut vend = Vec3::zeros();
                         vend[d] = d_i as f32;
                         vend[u] = left.0 as f32;
                         vend[v] = right.1 as f32;
-                        let vend = vert(vend, normal, b);
+                        let vend = vert(vend, normal, b, light);
 
                         // Triangle 1
                         vertices.push(vleft);
@@ -274,6 +323,7 @@ fn culled(grid: &Chunk, neighbors: Vec<Arc<RwLock<Chunk>>>, phase2: bool) -> Vec
 
 /// Greedy meshing as in https://0fps.net/2012/06/30/meshing-in-a-minecraft-game/
 fn greedy(grid: &Chunk, neighbors: Vec<Arc<RwLock<Chunk>>>, phase2: bool) -> Vec<Vertex> {
+    let registry = registry();
     let mut vertices = Vec::new();
 
     // Sweep on all three axes
@@ -288,30 +338,34 @@ fn greedy(grid: &Chunk, neighbors: Vec<Arc<RwLock<Chunk>>>, phase2: bool) -> Vec
         let fb = neighbor_axis(&neighbors, d);
 
         // The faces that need to be drawn
-        let mut culled = grid.cull_faces(d, (&fb.0.read().unwrap(), &fb.1.read().unwrap()), phase2);
+        let fb = (&*fb.0.read().unwrap(), &*fb.1.read().unwrap());
+        let mut culled = grid.cull_faces(d, fb, phase2, &registry);
         if culled.is_empty() {
             continue;
         }
+        let lights = grid.face_lights(d, fb);
 
         // The actual sweeping
         for d_i in 0..=CHUNK_SIZE as usize {
             let culled = &mut culled[d_i];
+            let lights = &lights[d_i];
             // Generate mesh
             for u_i in 0..CHUNK_SIZE as usize {
                 for v_i in 0..CHUNK_SIZE as usize {
                     let b = culled[u_i][v_i];
-                    if b != Material::Air {
+                    let light = lights[u_i][v_i];
+                    if b != Material::AIR {
                         // Add this face to the mesh, with any others that are adjacent
                         let left = (u_i, v_i);
                         let mut right = (u_i + 1, v_i + 1);
 
                         // Add to u
                         for u_i in (u_i + 1)..CHUNK_SIZE as usize {
-                            if culled[u_i][v_i] == b {
+                            if culled[u_i][v_i] == b && lights[u_i][v_i] == light {
                                 right.0 += 1;
 
                                 // We don't need to mesh this one anymore
-                                culled[u_i][v_i] = Material::Air;
+                                culled[u_i][v_i] = Material::AIR;
                             } else {
                                 break;
                             }
@@ -320,11 +374,13 @@ fn greedy(grid: &Chunk, neighbors: Vec<Arc<RwLock<Chunk>>>, phase2: bool) -> Vec
                         // Add to v
                         for v_i in (v_i + 1)..CHUNK_SIZE as usize {
                             // Sweep across the whole u extent of the current quad to make sure we can extend the whole thing
-                            if (left.0..right.0).all(|u_i| culled[u_i][v_i] == b) {
+                            if (left.0..right.0)
+                                .all(|u_i| culled[u_i][v_i] == b && lights[u_i][v_i] == light)
+                            {
                                 right.1 += 1;
 
                                 // We don't need to mesh this whole line anymore
-                                (left.0..right.0).for_each(|u_i| culled[u_i][v_i] = Material::Air);
+                                (left.0..right.0).for_each(|u_i| culled[u_i][v_i] = Material::AIR);
                             } else {
                                 break;
                             }
@@ -337,28 +393,28 @@ fn greedy(grid: &Chunk, neighbors: Vec<Arc<RwLock<Chunk>>>, phase2: bool) -> Vec
                         vleft[d] = d_i as f32;
                         vleft[u] = left.0 as f32;
                         vleft[v] = left.1 as f32;
-                        let vleft = vert(vleft, normal, b);
+                        let vleft = vert(vleft, normal, b, light);
 
                         // Top left
                         let mut vmid = Vec3::zeros();
                         vmid[d] = d_i as f32;
                         vmid[u] = right.0 as f32;
                         vmid[v] = left.1 as f32;
-                        let vmid = vert(vmid, normal, b);
+                        let vmid = vert(vmid, normal, b, light);
 
                         // Top right
                         let mut vright = Vec3::zeros();
                         vright[d] = d_i as f32;
                         vright[u] = right.0 as f32;
                         vright[v] = right.1 as f32;
-                        let vright = vert(vright, normal, b);
+                        let vright = vert(vright, normal, b, light);
 
                         // Bottom right
                         let mut vend = Vec3::zeros();
                         vend[d] = d_i as f32;
                         vend[u] = left.0 as f32;
                         vend[v] = right.1 as f32;
-                        let vend = vert(vend, normal, b);
+                        let vend = vert(vend, normal, b, light);
 
                         // Triangle 1
                         vertices.push(vleft);
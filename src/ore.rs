@@ -0,0 +1,92 @@
+use crate::common::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// One row of the ore rarity table in `GenConfig`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OreConfig {
+    /// The name of the material, from the registry
+    pub material: String,
+    /// Veins only start between `min_y` and `max_y`, and are most common halfway between them
+    pub min_y: i32,
+    pub max_y: i32,
+    /// The average number of veins per chunk at the most common height
+    pub veins: f64,
+    /// The number of blocks in each vein
+    pub size: usize,
+}
+
+pub fn default_ores() -> Vec<OreConfig> {
+    let ore = |material: &str, min_y, max_y, veins, size| OreConfig {
+        material: material.to_string(),
+        min_y,
+        max_y,
+        veins,
+        size,
+    };
+    vec![
+        ore("coal", -64, 48, 10.0, 12),
+        ore("copper", -96, 16, 6.0, 8),
+        ore("iron", -128, 0, 6.0, 8),
+        ore("gold", -256, -48, 2.5, 6),
+        ore("diamond", -512, -128, 1.0, 4),
+    ]
+}
+
+impl OreConfig {
+    /// How likely a vein is to start at height `y`, from 0 to 1
+    fn chance(&self, y: i32) -> f64 {
+        if y < self.min_y || y > self.max_y || self.max_y <= self.min_y {
+            return 0.0;
+        }
+        let mid = (self.min_y + self.max_y) as f64 / 2.0;
+        let half = (self.max_y - self.min_y) as f64 / 2.0;
+        1.0 - (y as f64 - mid).abs() / half
+    }
+}
+
+/// Where the ores in chunk `chunk` go, for the table `ores` with each row's material looked up in the registry.
+/// Veins are random walks starting in the chunk, and the parts that would leave the chunk are cut off.
+/// Ores should only replace stone, so they don't poke out of the ground or float in caves.
+pub fn veins(seed: u32, ores: &[(Material, OreConfig)], chunk: IVec3) -> HashMap<UVec3, Material> {
+    let mut ret = HashMap::new();
+    let start = chunk.map(|x| x * CHUNK_SIZE as i32);
+    let c = [chunk.x as i64, chunk.y as i64, chunk.z as i64];
+    let rand = |vals: &[i64]| hash(seed, &[&c[..], vals].concat());
+    let pick = |r: f64| (r * CHUNK_SIZE as f64).min(CHUNK_SIZE as f64 - 1.0) as i32;
+
+    for (i, (material, ore)) in ores.iter().enumerate() {
+        // Skip chunks out of the ore's range, so we don't waste time on most of them
+        if start.y + CHUNK_SIZE as i32 <= ore.min_y || start.y > ore.max_y {
+            continue;
+        }
+
+        let i = i as i64;
+        let tries = ore.veins.floor() as i64
+            + if rand(&[i, -1]) < ore.veins.fract() {
+                1
+            } else {
+                0
+            };
+        for t in 0..tries {
+            let mut pos = IVec3::new(
+                pick(rand(&[i, t, 0])),
+                pick(rand(&[i, t, 1])),
+                pick(rand(&[i, t, 2])),
+            );
+            if rand(&[i, t, 3]) >= ore.chance(start.y + pos.y) {
+                continue;
+            }
+
+            for s in 0..ore.size {
+                if pos.iter().all(|&x| x >= 0 && x < CHUNK_SIZE as i32) {
+                    ret.insert(pos.map(|x| x as usize), *material);
+                }
+                let r = rand(&[i, t, 4 + s as i64]);
+                let axis = (r * 6.0) as usize % 3;
+                pos[axis] += if r < 0.5 { 1 } else { -1 };
+            }
+        }
+    }
+    ret
+}
@@ -0,0 +1,340 @@
+// The stages chunks go through as they're generated, and the scheduler that runs them.
+// A stage can look at the chunks around the one it's working on, as long as they've gotten through the stage before,
+// so the scheduler generates neighbors as far as they need to go and keeps them until they're asked for themselves.
+// Only complete chunks ever come out, so nothing half-generated gets into the world or sent to players.
+
+use crate::common::*;
+use crate::generator::WorldGenerator;
+use crate::light::light_chunk;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// How far along a chunk is in being generated
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Status {
+    /// Nothing's been generated yet
+    Empty,
+    /// The biome layers and ores, with no caves yet
+    Terrain,
+    /// Caves, caverns and ravines are carved out
+    Carved,
+    /// Trees and other features are placed
+    Features,
+    /// Sky light and light from blocks are worked out, including what comes in from the chunks around it
+    Lit,
+    /// Ready to go in the world
+    Complete,
+}
+
+/// Chunks partway through the pipeline, and how far along they are
+pub type Pending = HashMap<IVec3, (Status, Chunk)>;
+
+/// One generation pass, which takes a chunk from the status before it to `status`
+pub struct Stage {
+    pub status: Status,
+    /// How far away, in chunks, the neighbors it looks at can be.
+    /// They all have to have gotten through the stage before first.
+    pub radius: i32,
+    /// Makes chunk `pos` from what it was after the stage before, given the chunks around it.
+    /// Chunks that aren't in `radius` might not be there, including ones going through the same stage.
+    pub pass: fn(&dyn WorldGenerator, IVec3, Chunk, &Pending) -> Chunk,
+}
+
+/// The stages every chunk goes through, in order
+pub fn stages() -> Vec<Stage> {
+    vec![
+        Stage {
+            status: Status::Terrain,
+            radius: 0,
+            pass: |gen, pos, _, _| gen.gen(pos),
+        },
+        Stage {
+            status: Status::Carved,
+            radius: 0,
+            pass: |gen, pos, chunk, _| gen.carve(chunk, pos),
+        },
+        // Features cross chunk borders, but `Gen::decorate` works out the ones from neighboring chunks from the noise,
+        // so it doesn't need to wait for them
+        Stage {
+            status: Status::Features,
+            radius: 0,
+            pass: |gen, pos, mut chunk, _| {
+                gen.decorate(&mut chunk, pos);
+                chunk
+            },
+        },
+        // Light spreads up to 15 blocks, so it can come in from any of the chunks around this one
+        Stage {
+            status: Status::Lit,
+            radius: 1,
+            pass: light,
+        },
+        Stage {
+            status: Status::Complete,
+            radius: 0,
+            pass: |_, _, mut chunk, _| {
+                chunk.compact();
+                chunk
+            },
+        },
+    ]
+}
+
+/// Lights chunk `pos` the way the client would with the chunks around it loaded, using their blocks as of the stage before
+fn light(_: &dyn WorldGenerator, pos: IVec3, chunk: Chunk, pending: &Pending) -> Chunk {
+    let mut chunks = HashMap::new();
+    let mut chunk = Some(chunk);
+    // Each one is lit as it's added, from the top down like they usually come in, so light spreads into chunks
+    // that are already lit instead of ones that are about to be lit over again
+    for y in (-1..=1).rev() {
+        for x in -1..=1 {
+            for z in -1..=1 {
+                let p = pos + IVec3::new(x, y, z);
+                let c = if p == pos {
+                    chunk.take().unwrap()
+                } else {
+                    pending[&p].1.clone()
+                };
+                chunks.insert(p, Arc::new(RwLock::new(c)));
+                light_chunk(&chunks, p);
+            }
+        }
+    }
+    let chunk = chunks.remove(&pos).unwrap();
+    Arc::try_unwrap(chunk).ok().unwrap().into_inner().unwrap()
+}
+
+pub struct Pipeline {
+    stages: Vec<Stage>,
+    /// Chunks that have started generating but aren't complete, because something else needed them
+    pending: Pending,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Pipeline {
+            stages: stages(),
+            pending: HashMap::new(),
+        }
+    }
+
+    fn status(&self, pos: IVec3) -> Status {
+        self.pending
+            .get(&pos)
+            .map(|(s, _)| *s)
+            .unwrap_or(Status::Empty)
+    }
+
+    /// Forgets about unfinished chunks we don't need anymore
+    pub fn retain(&mut self, mut f: impl FnMut(IVec3) -> bool) {
+        self.pending.retain(|&p, _| f(p))
+    }
+
+    /// Generates the chunks at `locs` all the way, along with as much of their neighbors as they need.
+    /// Each stage runs on all the chunks that need it at once.
+    pub fn run(&mut self, gen: &dyn WorldGenerator, locs: &[IVec3]) -> Vec<(IVec3, Chunk)> {
+        // How far each chunk needs to get, working backwards from the last stage
+        let mut need: HashMap<IVec3, Status> =
+            locs.iter().map(|&p| (p, Status::Complete)).collect();
+        for (i, stage) in self.stages.iter().enumerate().rev() {
+            let before = match i {
+                0 => Status::Empty,
+                i => self.stages[i - 1].status,
+            };
+            let r = stage.radius;
+            let todo: Vec<IVec3> = need
+                .iter()
+                .filter(|(&p, &s)| s >= stage.status && self.status(p) < stage.status)
+                .map(|(&p, _)| p)
+                .collect();
+            for p in todo {
+                for x in -r..=r {
+                    for y in -r..=r {
+                        for z in -r..=r {
+                            let s = need.entry(p + IVec3::new(x, y, z)).or_insert(before);
+                            *s = (*s).max(before);
+                        }
+                    }
+                }
+            }
+        }
+
+        for stage in &self.stages {
+            let todo: Vec<IVec3> = need
+                .iter()
+                .filter(|(&p, &s)| s >= stage.status && self.status(p) < stage.status)
+                .map(|(&p, _)| p)
+                .collect();
+            let pending = &mut self.pending;
+            // If the stage looks at its neighbors, the ones it's also working on have to stay how they were before it,
+            // so it gets copies; otherwise it can have the chunks themselves
+            let todo: Vec<(IVec3, Chunk)> = todo
+                .into_iter()
+                .map(|p| {
+                    let chunk = if stage.radius == 0 {
+                        pending.remove(&p).map(|(_, c)| c)
+                    } else {
+                        pending.get(&p).map(|(_, c)| c.clone())
+                    };
+                    (p, chunk.unwrap_or_else(Chunk::empty))
+                })
+                .collect();
+            let pending = &self.pending;
+            let done: Vec<(IVec3, Chunk)> = todo
+                .into_par_iter()
+                .map(|(p, chunk)| (p, (stage.pass)(gen, p, chunk, pending)))
+                .collect();
+            for (p, chunk) in done {
+                self.pending.insert(p, (stage.status, chunk));
+            }
+        }
+
+        locs.iter()
+            .filter_map(|p| self.pending.remove(p).map(|(_, c)| (*p, c)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::light::MAX_LIGHT;
+
+    /// Puts a number that depends on the chunk's position at its first block
+    struct Numbered;
+
+    impl WorldGenerator for Numbered {
+        fn gen(&self, pos: IVec3) -> Chunk {
+            let mut chunk = Chunk::empty();
+            chunk.set_block(UVec3::zeros(), number(pos));
+            chunk
+        }
+    }
+
+    fn number(pos: IVec3) -> Material {
+        Material((hash(7, &[pos.x as i64, pos.y as i64, pos.z as i64]) * 1000.0) as u16)
+    }
+
+    fn neighbors(pos: IVec3) -> impl Iterator<Item = IVec3> {
+        (-1..=1)
+            .flat_map(|x| (-1..=1).flat_map(move |y| (-1..=1).map(move |z| IVec3::new(x, y, z))))
+            .filter(|o| *o != IVec3::zeros())
+            .map(move |o| pos + o)
+    }
+
+    /// What `sum` should put at the second block of the chunk at `pos`
+    fn expected_sum(pos: IVec3) -> Material {
+        Material(neighbors(pos).fold(0u16, |acc, n| acc.wrapping_add(number(n).0)))
+    }
+
+    /// A radius 1 stage, which adds up the numbers of the chunks around it
+    fn sum(_: &dyn WorldGenerator, pos: IVec3, mut chunk: Chunk, pending: &Pending) -> Chunk {
+        let mut total = 0u16;
+        for n in neighbors(pos) {
+            let (status, neighbor) = pending
+                .get(&n)
+                .unwrap_or_else(|| panic!("neighbor {} of {} isn't there", n, pos));
+            assert!(*status >= Status::Terrain, "neighbor {} is {:?}", n, status);
+            total = total.wrapping_add(neighbor.block(UVec3::zeros()).0);
+        }
+        chunk.set_block(UVec3::new(1, 0, 0), Material(total));
+        chunk
+    }
+
+    /// Air, with a chunk of stone right above chunk 0
+    struct Roofed;
+
+    impl WorldGenerator for Roofed {
+        fn gen(&self, pos: IVec3) -> Chunk {
+            if pos == IVec3::y() {
+                Chunk::full(&|_| Material::STONE)
+            } else {
+                Chunk::empty()
+            }
+        }
+    }
+
+    fn pipeline() -> Pipeline {
+        Pipeline {
+            stages: vec![
+                Stage {
+                    status: Status::Terrain,
+                    radius: 0,
+                    pass: |gen, pos, _, _| gen.gen(pos),
+                },
+                Stage {
+                    status: Status::Carved,
+                    radius: 1,
+                    pass: sum,
+                },
+                Stage {
+                    status: Status::Complete,
+                    radius: 0,
+                    pass: |_, _, chunk, _| chunk,
+                },
+            ],
+            pending: HashMap::new(),
+        }
+    }
+
+    fn check(chunks: &[(IVec3, Chunk)]) {
+        for (pos, chunk) in chunks {
+            assert_eq!(chunk.block(UVec3::zeros()), number(*pos), "{}", pos);
+            assert_eq!(
+                chunk.block(UVec3::new(1, 0, 0)),
+                expected_sum(*pos),
+                "{}",
+                pos
+            );
+        }
+    }
+
+    #[test]
+    fn only_requested() {
+        let locs = [
+            IVec3::new(0, 0, 0),
+            IVec3::new(1, 0, 0),
+            IVec3::new(5, -2, 3),
+        ];
+        let chunks = pipeline().run(&Numbered, &locs);
+        assert_eq!(chunks.iter().map(|(p, _)| *p).collect::<Vec<_>>(), locs);
+        check(&chunks);
+    }
+
+    #[test]
+    fn any_order() {
+        let locs: Vec<IVec3> = (0..4)
+            .flat_map(|x| (0..3).map(move |z| IVec3::new(x, x - z, z)))
+            .collect();
+
+        let mut all = pipeline();
+        let mut at_once: HashMap<IVec3, Chunk> = all.run(&Numbered, &locs).into_iter().collect();
+
+        // The same chunks backwards a few at a time, so some have neighbors that are already partway or all the way done
+        let mut pieces = pipeline();
+        for part in locs.iter().rev().cloned().collect::<Vec<_>>().chunks(5) {
+            let chunks = pieces.run(&Numbered, part);
+            assert_eq!(chunks.len(), part.len());
+            check(&chunks);
+            for (pos, chunk) in chunks {
+                let other = at_once.remove(&pos).unwrap();
+                for b in [UVec3::zeros(), UVec3::new(1, 0, 0)] {
+                    assert_eq!(other.block(b), chunk.block(b), "{}", pos);
+                }
+            }
+        }
+        assert!(at_once.is_empty());
+    }
+
+    #[test]
+    fn lit_from_neighbors() {
+        let chunks = Pipeline::new().run(&Roofed, &[IVec3::zeros()]);
+        let sky = |x, y, z| chunks[0].1.light(UVec3::new(x, y, z)) >> 4;
+        // The stone keeps sky light from coming straight down, so it only comes in from the sides and fades out
+        assert_eq!(sky(16, 31, 16), 0);
+        assert_eq!(sky(31, 16, 16), MAX_LIGHT - 1);
+        assert_eq!(sky(0, 31, 16), MAX_LIGHT - 1);
+        assert_eq!(sky(16, 31, 25), MAX_LIGHT - 7);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::common::*;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+const CACHE_SIZE: usize = 16;
+
+/// Every region file starts with this, then `REGION_VERSION`
+const REGION_MAGIC: &[u8; 4] = b"VXRG";
+const REGION_VERSION: u32 = 1;
+/// The number of chunks in a region
+const REGION_LEN: usize = (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize;
+/// The magic bytes and version, then an `(offset, length, checksum)` entry for each chunk
+const HEADER_LEN: usize = 8 + REGION_LEN * 12;
+
+/// An LRU cache of regions, which are `REGION_SIZE`^3 cubes of chunks, stored as zstd-compressed bincode.
+///
+/// On disk, a region file is:
+/// - `REGION_MAGIC` and `REGION_VERSION`
+/// - An `(offset, length, checksum)` entry for each chunk, in `in_region()` order. A length of 0 means the chunk isn't stored.
+/// - The chunks themselves, checksummed with Adler-32
+///
+/// All the numbers are little-endian `u32`s.
+pub struct RegionCache {
+    indices: VecDeque<(IVec3, usize)>,
+    regions: Vec<Vec<Option<Vec<u8>>>>,
+    /// Whether each region in `regions` has changed since it was last written to disk
+    dirty: Vec<bool>,
+    path: PathBuf,
+}
+
+fn u32_at(buf: &[u8], i: usize) -> u32 {
+    let mut b = [0; 4];
+    b.copy_from_slice(&buf[i..i + 4]);
+    u32::from_le_bytes(b)
+}
+
+fn checksum(buf: &[u8]) -> u32 {
+    adler32::RollingAdler32::from_buffer(buf).hash()
+}
+
+impl RegionCache {
+    /// Stores regions in `path`, which is created if it doesn't exist
+    pub fn new(path: PathBuf) -> Self {
+        if !path.exists() {
+            std::fs::create_dir_all(&path).unwrap();
+        }
+
+        RegionCache {
+            indices: VecDeque::new(),
+            regions: Vec::new(),
+            dirty: Vec::new(),
+            path,
+        }
+    }
+
+    fn _store(&mut self, v: IVec3, mut region: Vec<Option<Vec<u8>>>) -> usize {
+        if self.indices.len() < CACHE_SIZE {
+            assert_eq!(self.regions.len(), self.indices.len());
+            self.regions.push(region);
+            self.dirty.push(false);
+            let i = self.regions.len() - 1;
+            self.indices.push_front((v, i));
+            i
+        } else {
+            let (nv, i) = self.indices.pop_back().unwrap();
+            self.indices.push_front((v, i));
+
+            std::mem::swap(&mut region, &mut self.regions[i]);
+
+            // Only bother writing it out if something changed
+            if self.dirty[i] {
+                self.write(nv, &region);
+            }
+            self.dirty[i] = false;
+
+            i
+        }
+    }
+
+    fn region_path(&self, v: IVec3, ext: &str) -> PathBuf {
+        let mut path = self.path.clone();
+        path.push(format!("{},{},{}.{}", v.x, v.y, v.z, ext));
+        path
+    }
+
+    fn write(&self, v: IVec3, region: &[Option<Vec<u8>>]) {
+        if let Err(e) = self.try_write(v, region) {
+            println!("Couldn't save region {:?}: {}", v, e);
+        }
+    }
+
+    /// Writes to a temporary file and then renames it, so a crash can't leave a half-written region behind
+    fn try_write(&self, v: IVec3, region: &[Option<Vec<u8>>]) -> std::io::Result<()> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(REGION_MAGIC);
+        header.extend_from_slice(&REGION_VERSION.to_le_bytes());
+        let mut offset = HEADER_LEN;
+        for chunk in region {
+            let (len, sum) = match chunk {
+                Some(chunk) => (chunk.len(), checksum(chunk)),
+                None => (0, 0),
+            };
+            header.extend_from_slice(&(offset as u32).to_le_bytes());
+            header.extend_from_slice(&(len as u32).to_le_bytes());
+            header.extend_from_slice(&sum.to_le_bytes());
+            offset += len;
+        }
+
+        let tmp = self.region_path(v, "region.tmp");
+        let mut f = File::create(&tmp)?;
+        f.write_all(&header)?;
+        for chunk in region.iter().flatten() {
+            f.write_all(chunk)?;
+        }
+        f.sync_all()?;
+        std::fs::rename(tmp, self.region_path(v, "region"))
+    }
+
+    /// Reads a region from disk. Anything missing or corrupt comes back as `None`, so it'll be regenerated.
+    fn read(&self, v: IVec3) -> Vec<Option<Vec<u8>>> {
+        let empty = || (0..REGION_LEN).map(|_| None).collect();
+
+        let path = self.region_path(v, "region");
+        if !path.exists() {
+            return empty();
+        }
+
+        let mut buf = Vec::new();
+        if let Err(e) = File::open(path).and_then(|mut f| f.read_to_end(&mut buf)) {
+            println!("Couldn't read region {:?}, regenerating it: {}", v, e);
+            return empty();
+        }
+        if buf.len() < HEADER_LEN || &buf[0..4] != REGION_MAGIC || u32_at(&buf, 4) != REGION_VERSION
+        {
+            println!("Region {:?} has a bad header, regenerating it", v);
+            return empty();
+        }
+
+        (0..REGION_LEN)
+            .map(|i| {
+                let entry = 8 + i * 12;
+                let offset = u32_at(&buf, entry) as usize;
+                let len = u32_at(&buf, entry + 4) as usize;
+                let sum = u32_at(&buf, entry + 8);
+                if len == 0 {
+                    return None;
+                }
+                match buf.get(offset..offset + len) {
+                    Some(chunk) if checksum(chunk) == sum => Some(chunk.to_vec()),
+                    _ => {
+                        println!("Chunk {} of region {:?} is corrupt, regenerating it", i, v);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Writes every region that's changed to disk
+    pub fn flush(&mut self) {
+        for &(v, i) in &self.indices {
+            if self.dirty[i] {
+                self.write(v, &self.regions[i]);
+            }
+        }
+        for d in &mut self.dirty {
+            *d = false;
+        }
+    }
+
+    fn _load(&mut self, v: IVec3) -> usize {
+        for i in 0..self.indices.len() {
+            if self.indices[i].0 == v {
+                let t = self.indices[i];
+                self.indices.remove(i);
+                self.indices.push_front(t);
+                return t.1;
+            }
+        }
+
+        // It's not in the cache, so load it from disk
+        let region = self.read(v);
+        self._store(v, region)
+    }
+
+    pub fn load(&mut self, chunk: IVec3) -> Option<Chunk> {
+        let v = chunk_to_region(chunk);
+        let idx = in_region(chunk);
+
+        let ri = self._load(v);
+        let data = self.regions[ri][idx].as_ref()?;
+        let c = zstd::stream::decode_all(&data[..])
+            .ok()
+            .and_then(|x| bincode::deserialize(&x).ok());
+        if c.is_none() {
+            println!("Couldn't decode chunk {:?}, regenerating it", chunk);
+        }
+        c
+    }
+
+    pub fn store(&mut self, pos: IVec3, mut chunk: Chunk) {
+        chunk.compact();
+        let ser = bincode::serialize(&chunk).unwrap();
+        let ser = zstd::stream::encode_all(&ser[..], 3).unwrap();
+
+        let v = chunk_to_region(pos);
+        let idx = in_region(pos);
+
+        let ri = self._load(v);
+        self.regions[ri][idx] = Some(ser);
+        self.dirty[ri] = true;
+    }
+}
@@ -2,15 +2,36 @@ use crate::chunk_thread::*;
 use crate::common::*;
 use crate::world::*;
 use std::collections::{HashMap, HashSet};
+use std::io::BufRead;
+use std::net::{TcpListener, ToSocketAddrs};
+use std::path::PathBuf;
 use std::rc::Rc;
 use std::sync::mpsc::*;
 use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 
 struct Player {
     pos: Vec3,
     conn: Rc<Connection>,
     id: usize,
+    name: String,
+    /// What they've picked up, so we know what they're allowed to place
+    inventory: HashMap<Material, usize>,
+}
+
+impl Player {
+    /// Their inventory the way it's saved in `WorldMeta`, in a stable order and without anything they've run out of
+    fn saved_inventory(&self) -> Vec<(Material, usize)> {
+        let mut v: Vec<_> = self
+            .inventory
+            .iter()
+            .filter(|(_, n)| **n > 0)
+            .map(|(m, n)| (*m, *n))
+            .collect();
+        v.sort_by_key(|(m, _)| m.0);
+        v
+    }
 }
 
 pub struct Server {
@@ -20,35 +41,418 @@ pub struct Server {
     orders: HashMap<IVec3, Vec<(usize, Rc<Connection>)>>,
     ch: (Sender<ChunkMessage>, Receiver<ChunkMessage>),
     config: Arc<GameConfig>,
+    listener: Option<TcpListener>,
+    /// Connections that haven't finished the handshake yet, and when they showed up
+    pending: Vec<(Connection, Instant)>,
+    next_id: usize,
+    meta: WorldMeta,
+    /// The world's save directory
+    dir: PathBuf,
+    /// When the server started, so we can keep track of the world's time
+    started: Instant,
+    /// Commands typed into the console, if we're reading them
+    console: Option<Receiver<String>>,
+    /// Chunks that have changed since they were last saved
+    dirty: HashSet<IVec3>,
+    /// When we last saved, for autosaving
+    saved: Instant,
+    /// What the `copy` command copied, for `paste`
+    clipboard: Option<Schematic>,
+}
+
+/// The console commands for editing the world, and what they take
+const EDIT_COMMANDS: &[(&str, &str)] = &[
+    ("fill", "x1 y1 z1 x2 y2 z2 material"),
+    ("replace", "x1 y1 z1 x2 y2 z2 from to"),
+    ("sphere", "x y z radius material"),
+    ("copy", "x1 y1 z1 x2 y2 z2"),
+    ("paste", "x y z [noair]"),
+];
+
+/// The chunks around a point that `Server::pregenerate` generates
+#[derive(Clone, Debug)]
+pub enum Area {
+    /// Every chunk within this many chunks of it
+    Radius(i32),
+    /// Every chunk within this many chunks of it along each axis
+    Box(IVec3),
+}
+
+impl Area {
+    /// Parses `16` as a radius, or `16x4x16` as a box
+    pub fn parse(s: &str) -> Option<Self> {
+        let n: Vec<i32> = s
+            .split('x')
+            .map(|x| x.parse().ok())
+            .collect::<Option<_>>()?;
+        match n[..] {
+            [r] => Some(Area::Radius(r)),
+            [x, y, z] => Some(Area::Box(IVec3::new(x, y, z))),
+            _ => None,
+        }
+    }
+
+    /// The chunks in the area around chunk `center`, a region at a time, with the closest regions and chunks first.
+    /// Going by region means each one is filled in while it's in the region cache, so it only gets written once.
+    fn chunks(&self, center: IVec3) -> Vec<IVec3> {
+        let size = match *self {
+            Area::Radius(r) => IVec3::new(r, r, r),
+            Area::Box(size) => size,
+        };
+        let mut chunks = Vec::new();
+        for x in -size.x..=size.x {
+            for y in -size.y..=size.y {
+                for z in -size.z..=size.z {
+                    let p = IVec3::new(x, y, z);
+                    match *self {
+                        Area::Radius(r) if p.map(|x| x as f32).norm() > r as f32 => (),
+                        _ => chunks.push(center + p),
+                    }
+                }
+            }
+        }
+        let distance = |a: IVec3, b: IVec3| ((a - b).map(|x| x as f32).norm() * 10.0) as i32;
+        let home = chunk_to_region(center);
+        chunks.sort_by_cached_key(|&a| {
+            let r = chunk_to_region(a);
+            (distance(r, home), r.x, r.y, r.z, distance(a, center))
+        });
+        chunks
+    }
 }
 
 impl Server {
-    /// Creates and starts a chunk thread, and creates a Server
-    pub fn new(config: Arc<GameConfig>) -> Self {
+    /// Loads or creates the world named in `config`, starts a chunk thread for it, and creates a Server.
+    /// Fails if `config` doesn't have a valid world name.
+    pub fn new(config: Arc<GameConfig>, materials: MaterialConfig) -> Result<Self, String> {
+        let dir = world_dir(&config.world)?;
+        let mut meta = WorldMeta::load_or_create(&dir, &config);
+        println!("Loaded world {:?} with seed {}", config.world, meta.seed);
+
+        // This has to happen before the generator looks up materials
+        set_registry(Registry::for_world(
+            materials.materials,
+            &mut meta.materials,
+        ));
+        meta.save(&dir);
+
         let (to, from_them) = channel();
         let (to_them, from) = channel();
         let c = Arc::clone(&config);
         let world = arcworld();
         let wc = Arc::clone(&world);
+        let chunk_thread = ChunkThread::new(c, wc, &meta, dir.clone(), to_them, from_them);
 
-        thread::spawn(move || ChunkThread::new(c, wc, to_them, from_them).run());
+        thread::spawn(move || chunk_thread.run());
 
-        Server {
+        Ok(Server {
             world,
             refs: HashMap::new(),
             players: Vec::new(),
             orders: HashMap::new(),
             ch: (to, from),
             config,
+            listener: None,
+            pending: Vec::new(),
+            next_id: 0,
+            meta,
+            dir,
+            started: Instant::now(),
+            console: None,
+            dirty: HashSet::new(),
+            saved: Instant::now(),
+            clipboard: None,
+        })
+    }
+
+    /// Start accepting remote players over TCP on `addr`; they're let in from the tick loop
+    pub fn listen(&mut self, addr: impl ToSocketAddrs) -> std::io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        self.listener = Some(listener);
+        Ok(())
+    }
+
+    /// Start reading commands from stdin, like `stop`; see `command()`
+    pub fn read_console(&mut self) {
+        let (to, from) = channel();
+        thread::spawn(move || {
+            // If stdin is closed, we just don't get any commands
+            for line in std::io::stdin().lock().lines().map_while(Result::ok) {
+                if to.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        self.console = Some(from);
+    }
+
+    /// Runs a command from the console, and returns false if it's time to shut down
+    fn command(&mut self, line: &str) -> bool {
+        match line.split_whitespace().collect::<Vec<_>>()[..] {
+            [] => (),
+            ["stop"] => {
+                println!("Stopping the server");
+                return false;
+            }
+            ["save"] => {
+                let n = self.save();
+                println!("Saved {} chunks", n);
+            }
+            [cmd, ref args @ ..] if EDIT_COMMANDS.iter().any(|(c, _)| *c == cmd) => {
+                match self.edit(cmd, args) {
+                    // `copy` says what it copied itself, since it doesn't change anything
+                    Ok(changed) => {
+                        if cmd != "copy" {
+                            println!("Changed {} chunks", changed.len());
+                        }
+                        self.resend(&changed);
+                    }
+                    Err(e) => println!("{}", e),
+                }
+            }
+            _ => {
+                println!("Unknown command {:?}; the commands are:", line);
+                println!("  stop\n  save");
+                for (cmd, args) in EDIT_COMMANDS {
+                    println!("  {} {}", cmd, args);
+                }
+            }
+        }
+        true
+    }
+
+    /// Runs one of `EDIT_COMMANDS`, and returns the chunks it changed.
+    /// Only loaded chunks are changed, like for players' edits.
+    fn edit(&mut self, cmd: &str, args: &[&str]) -> Result<Vec<IVec3>, String> {
+        let usage = || {
+            let (_, usage) = EDIT_COMMANDS.iter().find(|(c, _)| *c == cmd).unwrap();
+            format!("Usage: {} {}", cmd, usage)
+        };
+        let arg = |i: usize| args.get(i).ok_or_else(usage);
+        let block = |i: usize| -> Result<IVec3, String> {
+            let mut b = IVec3::zeros();
+            for a in 0..3 {
+                b[a] = arg(i + a)?.parse().map_err(|_| usage())?;
+            }
+            Ok(b)
+        };
+        // The corners can be in any order
+        let corners = || -> Result<(IVec3, IVec3), String> {
+            let (a, b) = (block(0)?, block(3)?);
+            Ok((a.zip_map(&b, i32::min), a.zip_map(&b, i32::max)))
+        };
+        let material = |i: usize| -> Result<Material, String> {
+            let name = arg(i)?;
+            Material::named(name).ok_or_else(|| format!("There's no material called {:?}", name))
+        };
+
+        let mut world = self.world.write().unwrap();
+        match (cmd, args.len()) {
+            ("fill", 7) => {
+                let (min, max) = corners()?;
+                Ok(world.fill_box(min, max, material(6)?))
+            }
+            ("replace", 8) => {
+                let (min, max) = corners()?;
+                Ok(world.replace(min, max, material(6)?, material(7)?))
+            }
+            ("sphere", 5) => {
+                let center = block(0)?.map(|x| x as f32 + 0.5);
+                let radius = arg(3)?.parse().map_err(|_| usage())?;
+                Ok(world.fill_sphere(center, radius, material(4)?))
+            }
+            ("copy", 6) => {
+                let (min, max) = corners()?;
+                let s = world.copy_region(min, max);
+                let size = s.size();
+                println!("Copied {}x{}x{} blocks", size.x, size.y, size.z);
+                self.clipboard = Some(s);
+                Ok(Vec::new())
+            }
+            ("paste", 3) | ("paste", 4) => {
+                let skip_air = match args.get(3) {
+                    None => false,
+                    Some(&"noair") => true,
+                    Some(_) => return Err(usage()),
+                };
+                let s = self
+                    .clipboard
+                    .as_ref()
+                    .ok_or_else(|| "Nothing's been copied yet".to_string())?;
+                Ok(world.paste_region(block(0)?, s, skip_air))
+            }
+            _ => Err(usage()),
+        }
+    }
+
+    /// Sends `chunks` again to everyone close enough to have them, after they've changed, and saves them next time
+    fn resend(&mut self, chunks: &[IVec3]) {
+        self.dirty.extend(chunks);
+        let world = self.world.read().unwrap();
+        for p in &self.players {
+            let c = world_to_chunk(p.pos);
+            let v: Vec<(IVec3, Chunk)> = chunks
+                .iter()
+                .filter(|&&i| (i - c).map(|x| x as f32).norm() <= self.config.draw_chunks as f32)
+                .filter_map(|&i| world.chunk(&i).map(|chunk| (i, chunk.clone())))
+                .collect();
+            if !v.is_empty() {
+                p.conn.send(Message::Chunks(v));
+            }
         }
     }
 
-    /// Add a player to the game
-    pub fn join(&mut self, conn: Connection, pos: Vec3) {
+    /// Saves the chunks that have changed since the last save, without unloading them, and returns how many there were
+    fn save(&mut self) -> usize {
+        let world = self.world.read().unwrap();
+        let mut n = 0;
+        for loc in self.dirty.drain() {
+            if let Some(chunk) = world.chunk(&loc) {
+                self.ch
+                    .0
+                    .send(ChunkMessage::UnloadChunk(loc, chunk.clone()))
+                    .unwrap();
+                n += 1;
+            }
+        }
+        drop(world);
+        self.ch.0.send(ChunkMessage::Flush).unwrap();
+        self.store_inventories();
+        // `time` counts from `started`, so that has to move up too or the time since starting would be counted twice
+        self.meta.time = self.time();
+        self.started = Instant::now();
+        self.meta.save(&self.dir);
+        self.saved = Instant::now();
+        n
+    }
+
+    /// Queues up a new connection; the player joins once they've sent a valid `Message::Hello`
+    pub fn connect(&mut self, conn: Connection) {
+        self.pending.push((conn, Instant::now()));
+    }
+
+    /// Queues up anyone waiting on the listener
+    fn accept(&mut self) {
+        let mut new = Vec::new();
+        if let Some(listener) = &self.listener {
+            while let Ok((stream, addr)) = listener.accept() {
+                match Connection::tcp(stream) {
+                    Ok(conn) => {
+                        println!("{} connected", addr);
+                        new.push(conn);
+                    }
+                    Err(e) => println!("Couldn't set up connection from {}: {}", addr, e),
+                }
+            }
+        }
+        for conn in new {
+            self.connect(conn);
+        }
+    }
+
+    /// Lets in or turns away pending connections that have introduced themselves
+    fn handshake(&mut self) {
+        let pending = std::mem::take(&mut self.pending);
+        for (conn, since) in pending {
+            match conn.recv() {
+                Some(Message::Hello {
+                    version,
+                    name,
+                    spawn,
+                }) => {
+                    if version != PROTOCOL_VERSION {
+                        println!(
+                            "Turned away {}, who's on protocol version {}",
+                            name, version
+                        );
+                        conn.send(Message::Reject(format!(
+                            "The server is on protocol version {}, but you're on version {}",
+                            PROTOCOL_VERSION, version
+                        )));
+                        continue;
+                    }
+                    // Inventories are saved by name, so two players with the same name could duplicate things
+                    if self.players.iter().any(|p| p.name == name) {
+                        println!("Turned away {}, who's already playing", name);
+                        conn.send(Message::Reject(format!(
+                            "Someone called {} is already playing",
+                            name
+                        )));
+                        continue;
+                    }
+                    let id = self.next_id;
+                    self.next_id += 1;
+                    let pos = self.clamp(spawn.unwrap_or(self.meta.spawn));
+                    let inventory = self
+                        .meta
+                        .inventories
+                        .get(&name)
+                        .cloned()
+                        .unwrap_or_default();
+                    if conn
+                        .send(Message::Welcome(Welcome {
+                            id,
+                            seed: self.meta.seed,
+                            spawn: pos,
+                            time: self.time(),
+                            materials: registry().defs(),
+                            border: self.meta.border,
+                            inventory: inventory.clone(),
+                        }))
+                        .is_some()
+                    {
+                        println!("{} joined as player {}", name, id);
+                        self.join(conn, id, name, pos, inventory);
+                    }
+                }
+                // They hung up before saying anything
+                Some(Message::Leave) => (),
+                Some(m) => {
+                    conn.send(Message::Reject(format!(
+                        "Expected a Hello, but got {:?}",
+                        m
+                    )));
+                }
+                None => {
+                    if since.elapsed() < LOGIN_TIMEOUT {
+                        self.pending.push((conn, since));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether chunk `chunk` is inside the world border, if there is one
+    fn inside(&self, chunk: IVec3) -> bool {
+        self.meta.border.is_none_or(|b| b.contains(chunk))
+    }
+
+    /// Moves `pos` inside the world border, if there is one
+    fn clamp(&self, pos: Vec3) -> Vec3 {
+        self.meta.border.map_or(pos, |b| b.clamp(pos))
+    }
+
+    /// How much time has passed in the world, in seconds
+    fn time(&self) -> f64 {
+        self.meta.time + self.started.elapsed().as_secs_f64()
+    }
+
+    /// Add a player to the game, with what they had when they last left
+    fn join(
+        &mut self,
+        conn: Connection,
+        id: usize,
+        name: String,
+        pos: Vec3,
+        inventory: Vec<(Material, usize)>,
+    ) {
         let new_player = Player {
             pos,
             conn: Rc::new(conn),
-            id: self.players.len(),
+            id,
+            name,
+            inventory: inventory.into_iter().collect(),
         };
         let (wait, load) = self.load_chunks_around(pos);
         //p.to_send.append(&mut wait);
@@ -59,7 +463,7 @@ impl Server {
                 .push((new_player.id, Rc::clone(&new_player.conn)));
         }
         if !load.is_empty() {
-            new_player.conn.send(Message::Chunks(load)).unwrap();
+            new_player.conn.send(Message::Chunks(load));
         }
         self.players.push(new_player);
     }
@@ -68,9 +472,24 @@ impl Server {
     pub fn run(mut self) {
         let mut running = true;
         while running {
+            self.accept();
+            self.handshake();
+
+            let commands: Vec<String> = match &self.console {
+                Some(console) => console.try_iter().collect(),
+                None => Vec::new(),
+            };
+            for line in commands {
+                running &= self.command(&line);
+            }
+            if self.saved.elapsed() >= Duration::from_secs(self.config.autosave) {
+                self.save();
+            }
+
             let mut p = Vec::new();
             std::mem::swap(&mut p, &mut self.players);
             let mut change = false;
+            let mut edits = Vec::new();
             self.players = p
                 .into_iter()
                 .filter_map(|mut p| {
@@ -78,22 +497,32 @@ impl Server {
                     while let Some(m) = p.conn.recv() {
                         match m {
                             Message::PlayerMove(n_pos) => {
-                                np = n_pos;
+                                np = self.clamp(n_pos);
                             }
                             Message::Leave => match *p.conn {
+                                // The player hosting this server quit, so the game's over
                                 Connection::Local(_, _) => {
                                     running = false;
                                     break;
                                 }
-                                _ => return None,
+                                _ => {
+                                    println!("{} left", p.name);
+                                    // In case they're still there, let them know they can go
+                                    p.conn.send(Message::Leave);
+                                    self.remove_player(&p);
+                                    change = true;
+                                    return None;
+                                }
                             },
-                            Message::SetBlock(p, b) => {
-                                self.world
-                                    .write()
-                                    .unwrap()
-                                    .set_block(p.map(|x| x as f32), b);
+                            Message::SetBlock(loc, b) => {
+                                match self.edit_block(&mut p, np, loc, b) {
+                                    Ok(()) => edits.push((p.id, loc, b)),
+                                    Err(actual) => {
+                                        p.conn.send(Message::BlockRejected(loc, b, actual));
+                                    }
+                                }
                             }
-                            _ => panic!("Hey, a client sent a message {:?}", m),
+                            _ => println!("Ignoring unexpected message from {}: {:?}", p.name, m),
                         }
                     }
                     let (wait, load) = self.load_chunk_diff(p.pos, np);
@@ -108,13 +537,19 @@ impl Server {
                             .push((p.id, Rc::clone(&p.conn)));
                     }
                     if !load.is_empty() {
-                        p.conn.send(Message::Chunks(load)).unwrap();
+                        p.conn.send(Message::Chunks(load));
                     }
                     p.pos = np;
                     Some(p)
                 })
                 .collect();
 
+            if !edits.is_empty() {
+                self.broadcast_edits(&edits);
+                self.dirty
+                    .extend(edits.iter().map(|&(_, loc, _)| split_block(loc).0));
+            }
+
             if change {
                 let p: Vec<Vec3> = self.players.iter().map(|x| x.pos).collect();
                 let p2: Vec<_> = p.iter().map(|x| world_to_chunk(*x)).collect();
@@ -137,6 +572,11 @@ impl Server {
                             let mut batches = HashMap::new();
                             let world = self.world.read().unwrap();
                             for i in &x {
+                                if !self.refs.contains_key(i) {
+                                    // Everyone who wanted this left or moved away while it was loading
+                                    self.orders.remove(i);
+                                    continue;
+                                }
                                 if let Some(v) = self.orders.remove(i) {
                                     if let Some(c) = world.chunk(i) {
                                         for (id, conn) in v {
@@ -156,42 +596,193 @@ impl Server {
                         for (_, (conn, v)) in batches {
                             conn.send(Message::Chunks(v));
                         }
-                    }
-                    ChunkMessage::UpdateChunks(v) => {
-                        let mut batches = HashMap::new();
-                        for i in v {
-                            for p in &self.players {
-                                if (world_to_chunk(p.pos) - i).map(|x| x as f32).norm()
-                                    <= self.config.draw_chunks as f32
-                                {
-                                    batches
-                                        .entry(p.id)
-                                        .or_insert((p.conn.clone(), Vec::new()))
-                                        .1
-                                        .push(i);
-                                }
-                            }
-                        }
-                        let world = self.world.read().unwrap();
-                        for (_, (conn, v)) in batches {
-                            conn.send(Message::Chunks(
-                                v.into_iter()
-                                    .filter_map(|x| world.chunks.get(&x).cloned().map(|y| (x, y)))
-                                    .collect(),
-                            ))
-                            .unwrap();
-                        }
+                        self.unload_unused(&x);
                     }
                     _ => panic!("Chunk thread sent {:?}", m),
                 }
             }
         }
         self.unload_all();
+        self.store_inventories();
+        self.meta.time = self.time();
+        self.meta.save(&self.dir);
         for p in self.players {
             p.conn.send(Message::Leave);
         }
     }
 
+    /// Generates and saves every chunk in `area` around `center`, or around spawn if it's `None`, and then shuts down.
+    /// Chunks go through the chunk thread like the ones players load, so chunks that are already saved aren't generated again.
+    pub fn pregenerate(mut self, area: Area, center: Option<Vec3>) {
+        if !self.config.save_chunks {
+            println!("Not pregenerating, since save_chunks is off in the config");
+            return;
+        }
+        let mut chunks = area.chunks(world_to_chunk(center.unwrap_or(self.meta.spawn)));
+        chunks.retain(|&p| self.inside(p));
+        let total = chunks.len();
+        println!("Pregenerating {} chunks", total);
+        self.ch.0.send(ChunkMessage::LoadChunks(chunks)).unwrap();
+
+        let timer = Instant::now();
+        let mut last = timer;
+        let mut done = 0;
+        while done < total {
+            match self.ch.1.recv().unwrap() {
+                ChunkMessage::LoadChunks(x) => {
+                    done += x.len();
+                    // Nobody's using them, so they go right back to the chunk thread to be saved
+                    self.unload_unused(&x);
+                }
+                m => panic!("Chunk thread sent {:?}", m),
+            }
+            if last.elapsed().as_secs() >= 1 || done == total {
+                println!(
+                    "{}/{} chunks ({:.1}%), {:.1} chunks/sec",
+                    done,
+                    total,
+                    done as f64 * 100.0 / total as f64,
+                    done as f64 / timer.elapsed().as_secs_f64()
+                );
+                last = Instant::now();
+            }
+        }
+        self.unload_all();
+        println!(
+            "Pregenerated {} chunks in {:.1} seconds",
+            total,
+            timer.elapsed().as_secs_f64()
+        );
+    }
+
+    /// Sets the block at `loc` to `new` for player `p`, if they're allowed to, and updates their inventory.
+    /// Otherwise, returns what's really there, or `None` if that chunk isn't loaded.
+    /// `pos` is where they are now, which might be newer than `p.pos`.
+    fn edit_block(
+        &self,
+        p: &mut Player,
+        pos: Vec3,
+        loc: IVec3,
+        new: Material,
+    ) -> Result<(), Option<Material>> {
+        if !self.inside(split_block(loc).0) {
+            return Err(None);
+        }
+        let block = loc.map(|x| x as f32);
+        let mut world = self.world.write().unwrap();
+        let old = world.block(block).ok_or(None)?;
+
+        // They can't reach through walls, and it's measured along the ray like the client's `trace`.
+        // Their position is a little out of date, so give them some slack.
+        match world.view_distance(pos, loc) {
+            Some(t) if t <= REACH + 2.0 => (),
+            _ => return Err(Some(old)),
+        }
+
+        if new == Material::AIR {
+            // Breaking a block, and what it drops goes in their inventory
+            if old == Material::AIR || old.phase2() || old.hardness() < 0.0 {
+                return Err(Some(old));
+            }
+            if let Some(d) = old.drops() {
+                *p.inventory.entry(d).or_insert(0) += 1;
+            }
+        } else {
+            // Placing a block, which has to come out of their inventory into empty space
+            if old != Material::AIR && !old.phase2() {
+                return Err(Some(old));
+            }
+            match p.inventory.get_mut(&new) {
+                Some(n) if *n > 0 => *n -= 1,
+                _ => return Err(Some(old)),
+            }
+        }
+
+        world.set_block(block, new);
+        Ok(())
+    }
+
+    /// Tells everyone who can see them about block changes other players made.
+    /// `edits` is `(player_id, block, new_material)`.
+    fn broadcast_edits(&self, edits: &[(usize, IVec3, Material)]) {
+        for p in &self.players {
+            let c = world_to_chunk(p.pos);
+            let v: Vec<_> = edits
+                .iter()
+                .filter(|(id, loc, _)| {
+                    *id != p.id
+                        && (world_to_chunk(loc.map(|x| x as f32)) - c)
+                            .map(|x| x as f32)
+                            .norm()
+                            <= self.config.draw_chunks as f32
+                })
+                .map(|(_, loc, b)| (*loc, *b))
+                .collect();
+            if !v.is_empty() {
+                p.conn.send(Message::BlockChanges(v));
+            }
+        }
+    }
+
+    /// Puts what everyone has into `meta`, so it's saved with the world
+    fn store_inventories(&mut self) {
+        for p in &self.players {
+            self.meta
+                .inventories
+                .insert(p.name.clone(), p.saved_inventory());
+        }
+    }
+
+    /// Forgets a player who's left, letting go of the chunks they were holding on to
+    fn remove_player(&mut self, p: &Player) {
+        self.meta
+            .inventories
+            .insert(p.name.clone(), p.saved_inventory());
+        self.meta.save(&self.dir);
+
+        // Leave the keys so we don't ask the chunk thread to load anything twice
+        for v in self.orders.values_mut() {
+            v.retain(|(id, _)| *id != p.id);
+        }
+
+        let chunk_pos = world_to_chunk(p.pos);
+        let draw_chunks = self.config.draw_chunks as i32;
+        let mut released = Vec::new();
+        for x in -draw_chunks..draw_chunks {
+            for y in -draw_chunks..draw_chunks {
+                for z in -draw_chunks..draw_chunks {
+                    let i = IVec3::new(x, y, z);
+                    if i.map(|x| x as f32).norm() <= self.config.draw_chunks as f32 {
+                        let i = chunk_pos + i;
+                        if let Some(r) = self.refs.get_mut(&i) {
+                            *r -= 1;
+                            if *r == 0 {
+                                self.refs.remove(&i);
+                                released.push(i);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        self.unload_unused(&released);
+    }
+
+    /// Sends any of `chunks` that nobody has a reference to back to the chunk thread
+    fn unload_unused(&mut self, chunks: &[IVec3]) {
+        let mut world = self.world.write().unwrap();
+        for i in chunks {
+            if !self.refs.contains_key(i) {
+                if let Some(chunk) = world.remove_chunk(i) {
+                    self.ch
+                        .0
+                        .send(ChunkMessage::UnloadChunk(*i, chunk))
+                        .unwrap();
+                }
+            }
+        }
+    }
+
     fn unload_all(&mut self) {
         let mut m = HashMap::new();
         std::mem::swap(&mut self.world.write().unwrap().chunks, &mut m);
@@ -223,7 +814,9 @@ impl Server {
             for y in -draw_chunks..draw_chunks {
                 for z in -draw_chunks..draw_chunks {
                     let p = IVec3::new(x, y, z);
-                    if p.map(|x| x as f32).norm() <= self.config.draw_chunks as f32 {
+                    if p.map(|x| x as f32).norm() <= self.config.draw_chunks as f32
+                        && self.inside(chunk_pos + p)
+                    {
                         to_load.push(chunk_pos + p);
                     }
                 }
@@ -281,8 +874,12 @@ impl Server {
                 for z in -draw_chunks..draw_chunks {
                     let p = IVec3::new(x, y, z);
                     if p.map(|x| x as f32).norm() <= self.config.draw_chunks as f32 {
-                        around_old.insert(chunk_old + p);
-                        around_new.insert(chunk_new + p);
+                        if self.inside(chunk_old + p) {
+                            around_old.insert(chunk_old + p);
+                        }
+                        if self.inside(chunk_new + p) {
+                            around_new.insert(chunk_new + p);
+                        }
                     }
                 }
             }
@@ -344,3 +941,32 @@ impl Server {
         (to_send, to_pass)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn area_by_region() {
+        let center = IVec3::new(REGION_SIZE - 2, 1, -3);
+        for area in [
+            Area::Radius(REGION_SIZE / 2 + 3),
+            Area::Box(IVec3::new(REGION_SIZE, 2, 5)),
+        ] {
+            let chunks = area.chunks(center);
+            assert_eq!(chunks.iter().collect::<HashSet<_>>().len(), chunks.len());
+            assert_eq!(chunks[0], center);
+
+            // Once we've left a region we never go back
+            let mut seen = HashSet::new();
+            for w in chunks.windows(2) {
+                let (a, b) = (chunk_to_region(w[0]), chunk_to_region(w[1]));
+                if a != b {
+                    seen.insert(a);
+                    assert!(!seen.contains(&b), "{:?} came back to region {}", area, b);
+                }
+            }
+            assert!(seen.len() > 1);
+        }
+    }
+}
@@ -116,16 +116,27 @@ vec3 bsdf(in vec3 V, in vec3 L, in vec3 N, in MatData mat) {
     return max(vec3(0.01) * max(nom,0.0) / denom,0.0);
 }
 
-vec3 shade(vec3 rd, vec3 normal, MatData mat, vec3 pos) {
+// Voxel light levels go from 0 to 15, and each one is a little dimmer than the last
+float light_fac(uint level) {
+    return level == 0u ? 0.0 : pow(0.8, 15.0 - float(level));
+}
+
+vec3 shade(vec3 rd, vec3 normal, MatData mat, vec3 pos, uint light) {
     vec3 sun_color = pow(vec3(0.7031,0.4687,0.1055), vec3(1.0 / 4.2));
     vec3 sky_color = pow(vec3(0.3984,0.5117,0.7305), vec3(1.0 / 4.2));
+    vec3 lamp_color = vec3(1.0, 0.75, 0.45);
 
     float sha = shadow(normal, pos);
+    // How much of the sky and how much light from blocks gets here
+    float sky_light = max(light_fac(light >> 4u), 0.02);
+    float block_light = light_fac(light & 15u);
 
     vec3 col = sha * sun_color * smoothstep(0.0, 0.1, sun_dir.y) * saturate(dot(normal, sun_dir));//saturate(bsdf(-rd, sun_dir, normal, mat));
-    col += sky_color * 0.2 * saturate(0.5 + 0.5*normal.y + 0.2*normal.x);//mat.color * IPI * length(abs(normal) * vec3(0.7, 1.0, 0.85));//bsdf(-rd, normalize(normal * vec3(1, 0, 1)), normal, mat);
+    col += sky_color * 0.2 * sky_light * saturate(0.5 + 0.5*normal.y + 0.2*normal.x);//mat.color * IPI * length(abs(normal) * vec3(0.7, 1.0, 0.85));//bsdf(-rd, normalize(normal * vec3(1, 0, 1)), normal, mat);
     col += sha * pow(sun_color, vec3(1.2)) * 0.2 * smoothstep(0.0, 0.1, sun_dir.y) * saturate(dot(normal, normalize(sun_dir * vec3(-1,0,-1))));//saturate(bsdf(-rd, -sun_dir, normal, mat));
 
+    col += lamp_color * 0.6 * block_light;
+
     col *= IPI * mat.color;
     if (mat.roughness < 0.2) {
         vec3 r = reflect(rd,normal);
@@ -139,7 +150,8 @@ void main() {
     vec4 g = texelFetch(gbuff, ivec2(gl_FragCoord.xy), 0);
     vec3 frag_pos = g.xyz;
     uint w = floatBitsToUint(g.w);
-    uint mat_index = w >> 3u;
+    uint mat_index = (w >> 3u) & 0xFFFFu;
+    uint light = w >> 19u;
     vec3 normal = decode_normal(w);
 
     vec3 col = vec3(0);
@@ -154,7 +166,7 @@ void main() {
     } else {
         vec3 rd = normalize(frag_pos - camera_pos);
         MatData mat = materials[mat_index];
-        col = shade(rd, normal, mat, frag_pos);
+        col = shade(rd, normal, mat, frag_pos, light);
         a = 1.0 - mat.trans;
     }
 
@@ -1,188 +1,471 @@
 extern crate noise;
+use crate::biome::*;
+use crate::cave::*;
 use crate::common::*;
-use crate::world::World;
+use crate::feature::*;
+use crate::generator::WorldGenerator;
+use crate::ore::*;
+use enum_iterator::IntoEnumIterator;
 use noise::*;
-// use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
+/// Settings for the terrain generator, which are saved with each world
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GenConfig {
+    pub octaves: usize,
+    pub persistence: f64,
+    /// The frequency of the heightmap noise, per meter
+    pub scale: f64,
+    /// The height of the tallest hills, in meters
+    pub height: f32,
+    /// The frequency of the climate noise that picks biomes, per meter
+    #[serde(default = "default_biome_scale")]
+    pub biome_scale: f64,
+    /// Whether to generate caves and overhangs.
+    /// This is off for worlds made before there were caves, so new chunks line up with the old ones.
+    #[serde(default)]
+    pub caves: bool,
+    /// The ore rarity table.
+    /// Ores only replace stone, so worlds made before ores can get them without seams.
+    #[serde(default = "default_ores")]
+    pub ores: Vec<OreConfig>,
+}
+
+fn default_biome_scale() -> f64 {
+    0.001
+}
+
+impl Default for GenConfig {
+    fn default() -> Self {
+        GenConfig {
+            octaves: 8,
+            persistence: 0.5,
+            scale: 0.0004,
+            height: 48.0,
+            biome_scale: default_biome_scale(),
+            caves: true,
+            ores: default_ores(),
+        }
+    }
+}
+
+/// The terrain generator. Everything it generates depends only on the seed and `GenConfig`,
+/// so the same world always comes out exactly the same, no matter when or in what order chunks are generated.
 pub struct Gen {
     noise: HybridMulti,
+    biomes: BiomeMap,
+    caves: Caves,
+    features: Vec<Box<dyn Feature>>,
+    /// The ore table from `config`, with the materials looked up
+    ores: Vec<(Material, OreConfig)>,
+    seed: u32,
+    config: GenConfig,
 }
 
 impl Gen {
-    pub fn new() -> Self {
+    pub fn new(seed: u32, config: GenConfig) -> Self {
         Gen {
             noise: HybridMulti::new()
-                .set_seed(1)
-                .set_octaves(8)
-                .set_persistence(0.5),
+                .set_seed(seed)
+                .set_octaves(config.octaves)
+                .set_persistence(config.persistence),
+            biomes: BiomeMap::new(seed, config.biome_scale),
+            caves: Caves::new(seed),
+            features: default_features(),
+            ores: config
+                .ores
+                .iter()
+                .filter_map(|o| match Material::named(&o.material) {
+                    Some(m) => Some((m, o.clone())),
+                    None => {
+                        println!("Skipping ore {:?}, since it isn't a material", o.material);
+                        None
+                    }
+                })
+                .collect(),
+            seed,
+            config,
         }
     }
 
-    /// Returns the chunks it modified besides the one it's decorating (neighbor chunks)
-    pub fn decorate(&self, world: &mut World, chunk: IVec3) -> Vec<IVec3> {
-        let mut modified = Vec::new();
+    /// The biome at the column containing `pos`
+    pub fn biome(&self, pos: Vec3) -> Biome {
+        self.biomes.biome(pos.x as f64, pos.z as f64)
+    }
 
-        let start = chunk.map(|x| x * CHUNK_SIZE as i32);
+    /// The height and biome of the column at `(x, z)`.
+    /// Heights are blended between neighboring biomes so there aren't cliffs at the borders.
+    fn column(&self, x: f64, z: f64) -> (f32, Biome) {
+        let n = self
+            .noise
+            .get([x * self.config.scale, z * self.config.scale]) as f32;
+        let height = Biome::into_enum_iter()
+            .zip(self.biomes.weights(x, z))
+            .map(|(b, w)| {
+                let d = b.data();
+                w * (d.base + d.hills * self.config.height * n)
+            })
+            .sum();
+        (height, self.biomes.biome(x, z))
+    }
 
-        let chunk_heightmap = (0..CHUNK_SIZE as usize)
+    /// The height and biome of each column in the chunk starting at `start`, indexed `[x][z]`
+    fn columns(&self, start: IVec3) -> Vec<Vec<(f32, Biome)>> {
+        (0..CHUNK_SIZE as usize)
             .map(move |x| {
                 (0..CHUNK_SIZE as usize)
-                    .map(move |z| {
-                        3.0 + 48.0
-                            * self.noise.get([
-                                (start.x as f64 + x as f64) * 0.0004,
-                                (start.z as f64 + z as f64) * 0.0004,
-                            ]) as f32
-                    })
+                    .map(move |z| self.column(start.x as f64 + x as f64, start.z as f64 + z as f64))
                     .collect::<Vec<_>>()
             })
-            .collect::<Vec<_>>();
+            .collect::<Vec<_>>()
+    }
 
-        let ntrees = (self.noise.get([
-            chunk.x as f64 * 0.04,
-            chunk.y as f64 * 0.04,
-            chunk.z as f64 * 0.04,
-        ]) * 14.0)
-            .abs() as i32;
-
-        for i in 0..ntrees {
-            // Attempt to generate a tree
-
-            // Modifier so every tree is different
-            let f = i as f64;
-
-            let fx = self
-                .noise
-                .get([
-                    chunk.x as f64 + f * 2.3,
-                    chunk.y as f64 - f,
-                    chunk.z as f64 + f,
-                ])
-                .abs()
-                / 1.5;
-            let x = (fx * CHUNK_SIZE as f64).min(CHUNK_SIZE as f64 - 1.0) as usize;
-            let fz = self
-                .noise
-                .get([
-                    chunk.x as f64 + f,
-                    chunk.y as f64 + f * 3.9,
-                    chunk.z as f64 - f * 0.91,
-                ])
-                .abs()
-                / 1.5;
-            let z = (fz * CHUNK_SIZE as f64).min(CHUNK_SIZE as f64 - 1.0) as usize;
-
-            let y = chunk_heightmap[x][z].ceil() as i32 + 1;
-            // Is it in this chunk? (instead of above or below)
-            if (y - start.y) > 0 && (y - start.y) < CHUNK_SIZE as i32 {
-                let x = start.x + x as i32;
-                let z = start.z + z as i32;
-
-                if world.block(Vec3::new(x as f32, (y - 1) as f32, z as f32))
-                    == Some(Material::Grass)
-                {
-                    let tree_height = (self.noise.get([
-                        chunk.x as f64 + fz,
-                        chunk.y as f64 - fx,
-                        chunk.z as f64 + y as f64,
-                    ]) * 8.0)
-                        .abs() as i32;
-
-                    // Trunk
-                    for y in y..y + tree_height {
-                        let v = Vec3::new(x as f32, y as f32, z as f32);
-                        if world_to_chunk(v) != chunk {
-                            modified.push(world_to_chunk(v));
-                        }
-                        world.set_block(v, Material::Wood);
-                    }
+    /// How far overhangs can reach above or below the heightmap
+    fn reach(&self) -> i32 {
+        if self.config.caves {
+            OVERHANG.ceil() as i32
+        } else {
+            0
+        }
+    }
 
-                    let canopy_width = (self.noise.get([
-                        chunk.x as f64 - fz * 2.3,
-                        chunk.y as f64 + fx * 3.0,
-                        chunk.z as f64 - y as f64,
-                    ]))
-                    .abs() as i32
-                        + 1;
-
-                    for x in x - canopy_width..x + 1 + canopy_width {
-                        for z in z - canopy_width..z + 1 + canopy_width {
-                            for y in y + tree_height - canopy_width..y + tree_height + canopy_width
-                            {
-                                let v = Vec3::new(x as f32, y as f32, z as f32);
-
-                                if world_to_chunk(v) != chunk {
-                                    modified.push(world_to_chunk(v));
-                                }
-
-                                if world.block(v) == Some(Material::Air) {
-                                    world.set_block(v, Material::Leaf);
-                                }
-                            }
-                        }
-                    }
-                }
+    /// Whether the block at `(x, y, z)` is part of the terrain before carving, in a column of height `height`
+    fn solid(&self, x: i32, y: i32, z: i32, height: f32) -> bool {
+        if self.config.caves {
+            self.caves.solid(x as f64, y, z as f64, height)
+        } else {
+            y <= height.ceil() as i32
+        }
+    }
+
+    /// The terrain block at `(x, y, z)`, before ores and features.
+    /// `solid` is whether that block and the four above it are solid, from `Gen::solid`.
+    fn terrain(
+        &self,
+        x: i32,
+        y: i32,
+        z: i32,
+        height: f32,
+        biome: Biome,
+        solid: &[bool],
+    ) -> Material {
+        let b = self.layers(x, y, z, biome, solid);
+        if self.carves(b, x, y, z, height) {
+            Material::AIR
+        } else {
+            b
+        }
+    }
+
+    /// `terrain`, before caves are carved out
+    fn layers(&self, x: i32, y: i32, z: i32, biome: Biome, solid: &[bool]) -> Material {
+        let data = biome.data();
+        if !solid[0] {
+            if y < 0 {
+                Material::WATER
+            } else {
+                Material::AIR
             }
+        } else if !solid[1] {
+            if y < 3 + self.noise.get([x as f64 * 0.04, z as f64 * 0.04]) as i32 {
+                Material::SAND
+            } else {
+                data.surface
+            }
+        } else if !solid[2] || !solid[3] || !solid[4] {
+            data.subsurface
+        } else {
+            Material::STONE
         }
+    }
+
+    /// Whether a cave carves out block `b` at `(x, y, z)`, in a column of height `height`
+    fn carves(&self, b: Material, x: i32, y: i32, z: i32, height: f32) -> bool {
+        self.config.caves
+            && b != Material::WATER
+            && b != Material::AIR
+            && self.caves.carved(x as f64, y, z as f64, height)
+    }
+
+    /// The top block of the column at `(x, z)`, before features.
+    /// It's `None` if there's a hole there, like a ravine.
+    pub fn surface(&self, x: i32, z: i32) -> Option<Site> {
+        self.site(x, z, self.column(x as f64, z as f64))
+    }
 
-        modified.sort_by_key(|x| (x.x, x.y, x.z));
-        modified.dedup();
+    /// The lowest and highest the surface of a column of height `height` can be
+    fn surface_range(&self, height: f32) -> (i32, i32) {
+        let top = height.ceil() as i32 + self.reach();
+        if self.config.caves {
+            (height.floor() as i32 - self.reach() - 8, top)
+        } else {
+            (top, top)
+        }
+    }
 
-        modified
+    fn site(&self, x: i32, z: i32, (height, biome): (f32, Biome)) -> Option<Site> {
+        let (bottom, top) = self.surface_range(height);
+        let solid: Vec<bool> = (bottom..top + 5)
+            .map(|y| self.solid(x, y, z, height))
+            .collect();
+        (bottom..=top).rev().find_map(|y| {
+            let i = (y - bottom) as usize;
+            let material = self.terrain(x, y, z, height, biome, &solid[i..i + 5]);
+            if material == Material::AIR {
+                None
+            } else {
+                Some(Site {
+                    pos: IVec3::new(x, y, z),
+                    material,
+                    biome,
+                })
+            }
+        })
     }
 
-    pub fn gen(&self, pos: IVec3) -> Chunk {
+    /// The features that start in chunk `chunk`, as indices into `self.features` and where they are.
+    /// This only depends on the seed and the terrain, so every chunk a feature reaches into agrees on where it is.
+    fn plan(&self, chunk: IVec3) -> Vec<(usize, Site)> {
+        let start = chunk.map(|x| x * CHUNK_SIZE as i32);
+        let biome = self.biome(chunk_to_world(chunk));
+        let mut ret = Vec::new();
+        for (i, f) in self.features.iter().enumerate() {
+            let mut rand = Rand::new(
+                self.seed,
+                &[chunk.x as i64, chunk.y as i64, chunk.z as i64, i as i64],
+            );
+            let freq = f.frequency(biome);
+            let tries = freq.floor() as i32 + if rand.next() < freq.fract() { 1 } else { 0 };
+            for _ in 0..tries {
+                let x = start.x + rand.range(0, CHUNK_SIZE as i32);
+                let z = start.z + rand.range(0, CHUNK_SIZE as i32);
+                let column = self.column(x as f64, z as f64);
+                // Don't bother looking for the surface if it can't be in this chunk
+                let (bottom, top) = self.surface_range(column.0);
+                if top < start.y || bottom >= start.y + CHUNK_SIZE as i32 {
+                    continue;
+                }
+                if let Some(site) = self.site(x, z, column) {
+                    if site.pos.y >= start.y && site.pos.y < start.y + CHUNK_SIZE as i32 {
+                        ret.push((i, site));
+                    }
+                }
+            }
+        }
+        ret
+    }
+}
+
+impl WorldGenerator for Gen {
+    fn gen(&self, pos: IVec3) -> Chunk {
         let start = pos.map(|x| x * CHUNK_SIZE as i32);
 
-        let chunk_heightmap = (0..CHUNK_SIZE as usize)
-            .map(move |x| {
-                (0..CHUNK_SIZE as usize)
-                    .map(move |z| {
-                        3.0 + 48.0
-                            * self.noise.get([
-                                (start.x as f64 + x as f64) * 0.0004,
-                                (start.z as f64 + z as f64) * 0.0004,
-                            ]) as f32
-                    })
-                    .collect::<Vec<_>>()
-            })
-            .collect::<Vec<_>>();
+        let columns = self.columns(start);
 
         // The whole chunk is above the ground, so we don't need to bother
         if start.y > 0
             && start.y
-                > chunk_heightmap
+                > columns
                     .iter()
                     .flatten()
-                    .map(|x| x.ceil() as i32)
+                    .map(|(x, _)| x.ceil() as i32)
                     .max()
                     .unwrap()
+                    + self.reach()
         {
             return Chunk::empty();
         }
 
+        // Whether each block is part of the terrain before carving, indexed `[x][z][y]`.
+        // It goes a few blocks past the top of the chunk so we know how deep under the surface the top blocks are.
+        let solid = (0..CHUNK_SIZE as usize)
+            .map(|x| {
+                (0..CHUNK_SIZE as usize)
+                    .map(|z| {
+                        let height = columns[x][z].0;
+                        (0..CHUNK_SIZE as i32 + 4)
+                            .map(|y| {
+                                self.solid(
+                                    start.x + x as i32,
+                                    start.y + y,
+                                    start.z + z as i32,
+                                    height,
+                                )
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let ores = veins(self.seed, &self.ores, pos);
+
         Chunk::full(&|p| {
-            let height = chunk_heightmap[p.x][p.z]; //3.0 + 4.0 * self.noise.get([(start.x as f64 + x as f64) * 0.01, (start.z as f64 + z as f64) * 0.01]) as f32;
-            let y = p.y as i32 + start.y;
-            if y == height.ceil() as i32 {
-                if y < 3 + self.noise.get([
-                    (start.x as f64 + p.x as f64) * 0.04,
-                    (start.z as f64 + p.z as f64) * 0.04,
-                ]) as i32
-                {
-                    Material::Sand
-                } else {
-                    Material::Grass
-                }
-            } else if y < height.ceil() as i32 && y > height as i32 - 3 {
-                Material::Dirt
-            } else if y < height as i32 {
-                Material::Stone
-            } else if y < 0 {
-                Material::Water
+            let biome = columns[p.x][p.z].1;
+            let b = self.layers(
+                start.x + p.x as i32,
+                start.y + p.y as i32,
+                start.z + p.z as i32,
+                biome,
+                &solid[p.x][p.z][p.y..p.y + 5],
+            );
+            if b == Material::STONE {
+                ores.get(&p).cloned().unwrap_or(b)
             } else {
-                Material::Air
+                b
             }
         })
     }
+
+    fn carve(&self, chunk: Chunk, pos: IVec3) -> Chunk {
+        match chunk.uniform() {
+            Some(Material::AIR) | Some(Material::WATER) => return chunk,
+            _ if !self.config.caves => return chunk,
+            _ => (),
+        }
+        let start = pos.map(|x| x * CHUNK_SIZE as i32);
+        let columns = self.columns(start);
+        Chunk::full(&|p| {
+            let b = chunk.block(p);
+            let height = columns[p.x][p.z].0;
+            let (x, y, z) = (
+                start.x + p.x as i32,
+                start.y + p.y as i32,
+                start.z + p.z as i32,
+            );
+            if self.carves(b, x, y, z, height) {
+                Material::AIR
+            } else {
+                b
+            }
+        })
+    }
+
+    /// Adds features to `chunk`, which was just generated at `pos`.
+    /// This places the parts of features from this chunk and its neighbors that are inside this chunk,
+    /// so it doesn't need to wait for its neighbors or modify them.
+    fn decorate(&self, chunk: &mut Chunk, pos: IVec3) {
+        let start = pos.map(|x| x * CHUNK_SIZE as i32);
+        let end = start.map(|x| x + CHUNK_SIZE as i32 - 1);
+
+        let mut plans = Vec::new();
+        for x in -1..=1 {
+            for y in -1..=1 {
+                for z in -1..=1 {
+                    plans.append(&mut self.plan(pos + IVec3::new(x, y, z)));
+                }
+            }
+        }
+        // The same order in every chunk, so features that overlap across a border overlap the same way on both sides
+        plans.sort_by_key(|(i, site)| (site.pos.x, site.pos.y, site.pos.z, *i));
+
+        for (i, site) in plans {
+            let f = &self.features[i];
+            let r = f.radius();
+            if (0..3).any(|a| site.pos[a] + r < start[a] || site.pos[a] - r > end[a]) {
+                continue;
+            }
+
+            let mut rand = Rand::new(
+                self.seed,
+                &[
+                    site.pos.x as i64,
+                    site.pos.y as i64,
+                    site.pos.z as i64,
+                    i as i64,
+                ],
+            );
+            for b in f.place(self, site, &mut rand) {
+                let p = b.pos - start;
+                if p.iter().any(|&x| x < 0 || x >= CHUNK_SIZE as i32) {
+                    continue;
+                }
+                let p = p.map(|x| x as usize);
+                if b.replace || chunk.block(p) == Material::AIR {
+                    chunk.set_block(p, b.material);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::generate;
+    use std::collections::HashMap;
+
+    /// Some chunks around the surface and underground, including negative ones
+    fn locs() -> Vec<IVec3> {
+        let mut locs = Vec::new();
+        for x in -1..=1 {
+            for z in -1..=1 {
+                for y in -1..=0 {
+                    locs.push(IVec3::new(x, y, z));
+                }
+            }
+        }
+        locs.push(IVec3::new(-7, -3, 12));
+        locs
+    }
+
+    /// A 64-bit FNV-1a hash of the chunk's block ids, which is the same on every platform and Rust version
+    fn hash_chunk(chunk: &Chunk) -> u64 {
+        let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+        for x in 0..CHUNK_SIZE as usize {
+            for y in 0..CHUNK_SIZE as usize {
+                for z in 0..CHUNK_SIZE as usize {
+                    for &b in &chunk.block(UVec3::new(x, y, z)).0.to_le_bytes() {
+                        h = (h ^ b as u64).wrapping_mul(0x100_0000_01b3);
+                    }
+                }
+            }
+        }
+        h
+    }
+
+    /// The hash of each chunk in `locs`, generated in that order
+    fn hashes(gen: &Gen, locs: &[IVec3]) -> HashMap<IVec3, u64> {
+        locs.iter()
+            .map(|&p| (p, hash_chunk(&generate(gen, p))))
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_same_chunks() {
+        let locs = locs();
+        let a = hashes(&Gen::new(7, GenConfig::default()), &locs);
+
+        // A different order, on a different generator, so nothing can carry over from one chunk to the next
+        let mut shuffled = locs.clone();
+        shuffled.reverse();
+        shuffled.rotate_left(5);
+        let b = hashes(&Gen::new(7, GenConfig::default()), &shuffled);
+
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn different_seed_different_chunks() {
+        let locs = [IVec3::new(0, -1, 0), IVec3::new(0, 0, 0)];
+        let a = hashes(&Gen::new(7, GenConfig::default()), &locs);
+        let b = hashes(&Gen::new(8, GenConfig::default()), &locs);
+        assert_ne!(a, b);
+    }
+
+    /// Catches anything that changes what a seed generates, so existing worlds would get seams where old and new chunks meet.
+    /// If it's on purpose, update the hashes.
+    #[test]
+    fn golden() {
+        let gen = Gen::new(7, GenConfig::default());
+        let expected = [
+            (IVec3::new(0, -1, 0), 0x387d_ddf6_22c0_5462),
+            (IVec3::new(0, 0, 0), 0xc00a_9b8f_c68e_a687),
+            (IVec3::new(-7, -3, 12), 0xe8a7_da8c_3c8b_6cd6),
+            (IVec3::new(3, 1, -5), 0x99d0_2bca_6baa_329c),
+        ];
+        for &(pos, hash) in &expected {
+            assert_eq!(hash_chunk(&generate(&gen, pos)), hash, "chunk {}", pos);
+        }
+    }
 }
@@ -1,5 +1,11 @@
 use crate::common::*;
+use crate::generator::WorldType;
+use crate::terrain::GenConfig;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
 
 pub struct World {
@@ -18,10 +24,6 @@ impl World {
         }
     }
 
-    pub fn contains_chunk(&self, chunk: IVec3) -> bool {
-        self.chunks.contains_key(&chunk)
-    }
-
     pub fn locs(&self) -> std::collections::hash_map::Keys<'_, IVec3, Chunk> {
         self.chunks.keys()
     }
@@ -41,11 +43,198 @@ impl World {
         let chunk = self.chunks.get(&chunk)?;
         Some(chunk.block(in_chunk))
     }
-    pub fn set_block(&mut self, k: Vec3, v: Material) {
+    /// Returns the block that was there before, or `None` if that chunk isn't loaded
+    pub fn set_block(&mut self, k: Vec3, v: Material) -> Option<Material> {
         let chunk = world_to_chunk(k);
         let in_chunk = in_chunk(k);
-        let chunk = self.chunks.get_mut(&chunk).unwrap();
+        let chunk = self.chunks.get_mut(&chunk)?;
+        let old = chunk.block(in_chunk);
         chunk.set_block(in_chunk, v);
+        Some(old)
+    }
+
+    /// The first block the ray from `ro` in direction `rd` hits, if it's within `max_t`; see `raycast`
+    pub fn raycast(&self, ro: Vec3, rd: Vec3, max_t: f32) -> Option<Hit> {
+        raycast(ro, rd, max_t, |p| {
+            let (chunk, idx) = split_block(p);
+            self.chunks.get(&chunk).map(|c| c.block(idx))
+        })
+    }
+
+    /// How far `eye` is from block `target` along a ray with nothing solid in the way, or `None` if it's hidden.
+    /// It's enough for any part of it to be in view, so this checks toward the middle of the block and of each side,
+    /// just inside it so looking along the ground still works, and returns the shortest.
+    pub fn view_distance(&self, eye: Vec3, target: IVec3) -> Option<f32> {
+        let center = target.map(|x| x as f32 + 0.5);
+        let mut points = vec![center];
+        for a in 0..3 {
+            for &side in &[-0.49, 0.49] {
+                let mut p = center;
+                p[a] += side;
+                points.push(p);
+            }
+        }
+        points
+            .into_iter()
+            .filter_map(|p| {
+                let d = (p - eye).norm();
+                match self.raycast(eye, p - eye, d) {
+                    Some(hit) if hit.pos == target => Some(hit.t),
+                    Some(_) => None,
+                    // It's empty, so the ray didn't hit anything on the way there
+                    None => Some(d),
+                }
+            })
+            .min_by(|a, b| a.partial_cmp(b).unwrap())
+    }
+}
+
+/// The chunks that overlap the box from `min` to `max`, in order
+fn chunks_in(min: IVec3, max: IVec3) -> impl Iterator<Item = IVec3> {
+    let (lo, _) = split_block(min);
+    let (hi, _) = split_block(max);
+    (lo.x..=hi.x).flat_map(move |x| {
+        (lo.y..=hi.y).flat_map(move |y| (lo.z..=hi.z).map(move |z| IVec3::new(x, y, z)))
+    })
+}
+
+/// The corner of chunk `chunk`, and the part of the box from `min` to `max` inside it in chunk coordinates
+fn overlap(chunk: IVec3, min: IVec3, max: IVec3) -> (IVec3, UVec3, UVec3) {
+    let start = chunk.map(|x| x * CHUNK_SIZE as i32);
+    (
+        start,
+        (min - start).map(|x| x.max(0) as usize),
+        (max - start).map(|x| x.min(CHUNK_SIZE as i32 - 1) as usize),
+    )
+}
+
+// Bulk editing, for world editing tools and the like.
+// Boxes are in block coordinates, include both corners, and can span any number of chunks.
+// Chunks that aren't loaded are left alone, and each edit returns the chunks it changed, in order.
+impl World {
+    /// Calls `f` with each loaded chunk overlapping the box from `min` to `max`, and returns the chunks it returned true for.
+    /// `f` gets the chunk, its corner, and the part of the box inside it.
+    fn edit(
+        &mut self,
+        min: IVec3,
+        max: IVec3,
+        mut f: impl FnMut(&mut Chunk, IVec3, UVec3, UVec3) -> bool,
+    ) -> Vec<IVec3> {
+        chunks_in(min, max)
+            .filter(|c| match self.chunks.get_mut(c) {
+                Some(chunk) => {
+                    let (start, lo, hi) = overlap(*c, min, max);
+                    f(chunk, start, lo, hi)
+                }
+                None => false,
+            })
+            .collect()
+    }
+
+    pub fn fill_box(&mut self, min: IVec3, max: IVec3, m: Material) -> Vec<IVec3> {
+        self.edit(min, max, |chunk, _, lo, hi| chunk.fill_box(lo, hi, m))
+    }
+
+    /// Fills every block with its center within `radius` of `center`
+    pub fn fill_sphere(&mut self, center: Vec3, radius: f32, m: Material) -> Vec<IVec3> {
+        let min = (center - Vec3::repeat(radius)).map(|x| x.floor() as i32);
+        let max = (center + Vec3::repeat(radius)).map(|x| x.ceil() as i32);
+        self.edit(min, max, |chunk, start, _, _| {
+            chunk.fill_sphere(center - start.map(|x| x as f32), radius, m)
+        })
+    }
+
+    /// Turns every `from` block between `min` and `max` into `to`
+    pub fn replace(&mut self, min: IVec3, max: IVec3, from: Material, to: Material) -> Vec<IVec3> {
+        self.edit(min, max, |chunk, _, lo, hi| chunk.replace(lo, hi, from, to))
+    }
+
+    /// Copies the blocks from `min` to `max`; anything in chunks that aren't loaded is air
+    pub fn copy_region(&self, min: IVec3, max: IVec3) -> Schematic {
+        let mut s = Schematic::new((max - min).map(|x| (x + 1).max(0) as usize));
+        for c in chunks_in(min, max) {
+            if let Some(chunk) = self.chunks.get(&c) {
+                let (start, lo, hi) = overlap(c, min, max);
+                let at = (start - min).map(|x| x.max(0) as usize);
+                s.blit(at, &chunk.copy_region(lo, hi));
+            }
+        }
+        s
+    }
+
+    /// Pastes `s` with its corner at `at`. If `skip_air` is true, air in `s` doesn't replace anything.
+    pub fn paste_region(&mut self, at: IVec3, s: &Schematic, skip_air: bool) -> Vec<IVec3> {
+        let max = at + s.size().map(|x| x as i32) - IVec3::repeat(1);
+        self.edit(at, max, |chunk, start, _, _| {
+            chunk.paste_region(at - start, s, skip_air)
+        })
+    }
+}
+
+/// What a ray hit, from `raycast`
+#[derive(Clone, Copy, Debug)]
+pub struct Hit {
+    /// The block it hit
+    pub pos: IVec3,
+    /// The side of the block it hit, pointing out of the block. It's zero if the ray started inside the block.
+    pub normal: IVec3,
+    pub material: Material,
+    /// How far along the ray it gets to the block, which is zero if the ray started inside it
+    pub t: f32,
+}
+
+/// Walks through each block the ray from `ro` in direction `rd` goes through, and finds the first one you can break,
+/// so it skips air and phase 2 blocks like water.
+/// `block` looks up blocks, and returns `None` if that chunk isn't loaded, which stops the ray.
+pub fn raycast(
+    ro: Vec3,
+    rd: Vec3,
+    max_t: f32,
+    block: impl Fn(IVec3) -> Option<Material>,
+) -> Option<Hit> {
+    if rd == Vec3::zeros() {
+        return None;
+    }
+    let rd = rd.normalize();
+
+    let mut pos = ro.map(|x| x.floor() as i32);
+    let step = rd.map(|x| x.signum() as i32);
+    // How far along the ray it is from one side of a block to the other, along each axis
+    let delta = rd.map(|x| 1.0 / x.abs());
+    // How far along the ray the next block is along each axis
+    let mut next = Vec3::from_fn(|a, _| {
+        if rd[a] == 0.0 {
+            f32::INFINITY
+        } else if rd[a] > 0.0 {
+            (pos[a] as f32 + 1.0 - ro[a]) * delta[a]
+        } else {
+            (ro[a] - pos[a] as f32) * delta[a]
+        }
+    });
+    let mut normal = IVec3::zeros();
+    let mut t = 0.0;
+    let registry = registry();
+
+    loop {
+        let material = block(pos)?;
+        if material != Material::AIR && !registry.get(material).phase2 {
+            return Some(Hit {
+                pos,
+                normal,
+                material,
+                t,
+            });
+        }
+
+        let a = next.imin();
+        t = next[a];
+        if t > max_t {
+            return None;
+        }
+        pos[a] += step[a];
+        next[a] += delta[a];
+        normal = IVec3::zeros();
+        normal[a] = -step[a];
     }
 }
 
@@ -54,3 +243,447 @@ impl Extend<(IVec3, Chunk)> for World {
         self.chunks.extend(it);
     }
 }
+
+/// Everything about a world besides its chunks, which is saved in `world.ron` in the world's directory
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WorldMeta {
+    pub seed: u32,
+    pub gen: GenConfig,
+    /// Worlds made before there were other kinds are normal
+    #[serde(default)]
+    pub world_type: WorldType,
+    /// The world border, if it has one
+    #[serde(default)]
+    pub border: Option<Border>,
+    pub spawn: Vec3,
+    /// When the world was created, in seconds since the Unix epoch
+    pub created: u64,
+    /// How much time has passed in the world, in seconds
+    pub time: f64,
+    /// The id of each material by name, so ids don't change when the registry does
+    #[serde(default)]
+    pub materials: HashMap<String, u16>,
+    /// What each player had when they last left, by name
+    #[serde(default)]
+    pub inventories: HashMap<String, Vec<(Material, usize)>>,
+}
+
+/// The directory the world called `name` is saved in, which is created if it doesn't exist yet.
+/// Names are used as directory names, so they can't be empty, `.` or `..`, or have slashes in them.
+pub fn world_dir(name: &str) -> Result<PathBuf, String> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+        return Err(format!("{:?} isn't a valid world name", name));
+    }
+    let mut path = app_dirs2::app_root(app_dirs2::AppDataType::UserData, &crate::APP_INFO).unwrap();
+    path.push("worlds");
+    path.push(name);
+    if !path.exists() {
+        std::fs::create_dir_all(&path)
+            .map_err(|e| format!("Couldn't create {}: {}", path.display(), e))?;
+    }
+    Ok(path)
+}
+
+impl WorldMeta {
+    pub fn new(seed: u32, gen: GenConfig, world_type: WorldType, border: Option<Border>) -> Self {
+        WorldMeta {
+            seed,
+            gen,
+            world_type,
+            border,
+            spawn: Vec3::new(4.0, 16.0, 4.0),
+            created: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |x| x.as_secs()),
+            time: 0.0,
+            materials: HashMap::new(),
+            inventories: HashMap::new(),
+        }
+    }
+
+    /// Loads the world in `dir`, or creates a new one there if there isn't one yet.
+    /// New worlds use the seed, world type, border and generator settings in `config`, or a random seed if it doesn't have one.
+    pub fn load_or_create(dir: &Path, config: &GameConfig) -> Self {
+        let path = dir.join("world.ron");
+        if path.exists() {
+            let meta: WorldMeta =
+                ron::de::from_reader(File::open(path).unwrap()).expect("bad world.ron");
+            if config.seed.is_some_and(|x| x != meta.seed) {
+                println!(
+                    "Ignoring the seed in the config, since this world already has seed {}",
+                    meta.seed
+                );
+            }
+            meta
+        } else {
+            let seed = config.seed.unwrap_or_else(rand::random);
+            let meta = WorldMeta::new(
+                seed,
+                config.gen.clone(),
+                config.world_type.clone(),
+                config.border,
+            );
+            meta.save(dir);
+            meta
+        }
+    }
+
+    pub fn save(&self, dir: &Path) {
+        if let Err(e) = self.try_save(dir) {
+            println!("Couldn't save world.ron: {}", e);
+        }
+    }
+
+    /// Writes to a temporary file and then renames it, like regions, so a crash can't leave a half-written `world.ron`
+    fn try_save(&self, dir: &Path) -> std::io::Result<()> {
+        let tmp = dir.join("world.ron.tmp");
+        let mut f = File::create(&tmp)?;
+        writeln!(f, "{}", ron::ser::to_string(self).unwrap())?;
+        f.sync_all()?;
+        std::fs::rename(tmp, dir.join("world.ron"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Looks blocks up in a list, with everything else air
+    fn blocks(list: &[(IVec3, Material)]) -> impl Fn(IVec3) -> Option<Material> + '_ {
+        move |p| {
+            Some(
+                list.iter()
+                    .find(|(q, _)| *q == p)
+                    .map_or(Material::AIR, |(_, m)| *m),
+            )
+        }
+    }
+
+    fn v(x: i32, y: i32, z: i32) -> IVec3 {
+        IVec3::new(x, y, z)
+    }
+
+    #[test]
+    fn axis_aligned() {
+        let list = [(v(5, 0, 0), Material::STONE), (v(0, -3, 0), Material::DIRT)];
+        let ro = Vec3::new(0.5, 0.5, 0.5);
+
+        let hit = raycast(ro, Vec3::new(1.0, 0.0, 0.0), 10.0, blocks(&list)).unwrap();
+        assert_eq!((hit.pos, hit.normal), (v(5, 0, 0), v(-1, 0, 0)));
+        assert_eq!(hit.material, Material::STONE);
+        assert_eq!(hit.t, 4.5);
+
+        // The distance is along the normalized ray
+        let hit = raycast(ro, Vec3::new(0.0, -2.0, 0.0), 10.0, blocks(&list)).unwrap();
+        assert_eq!((hit.pos, hit.normal), (v(0, -3, 0), v(0, 1, 0)));
+        assert_eq!(hit.t, 2.5);
+
+        assert!(raycast(ro, Vec3::new(0.0, 0.0, 1.0), 10.0, blocks(&list)).is_none());
+        assert!(raycast(ro, Vec3::zeros(), 10.0, blocks(&list)).is_none());
+    }
+
+    #[test]
+    fn diagonal() {
+        // A wall at x = 4
+        let wall = |p: IVec3| {
+            Some(if p.x == 4 {
+                Material::STONE
+            } else {
+                Material::AIR
+            })
+        };
+        let ro = Vec3::new(0.5, 0.5, 0.5);
+
+        // It gets to the wall 3.5 blocks along x, and 1.75 up
+        let hit = raycast(ro, Vec3::new(1.0, 0.5, 0.0), 10.0, wall).unwrap();
+        assert_eq!((hit.pos, hit.normal), (v(4, 2, 0), v(-1, 0, 0)));
+        assert!((hit.t - 3.5 * 1.25f32.sqrt()).abs() < 1e-5, "{}", hit.t);
+
+        let hit = raycast(ro, Vec3::new(1.0, 0.5, -0.75), 10.0, wall).unwrap();
+        assert_eq!((hit.pos, hit.normal), (v(4, 2, -3), v(-1, 0, 0)));
+        assert!((hit.t - 3.5 * 1.8125f32.sqrt()).abs() < 1e-5, "{}", hit.t);
+    }
+
+    #[test]
+    fn negative() {
+        let list = [
+            (v(-6, 0, -1), Material::STONE),
+            (v(-1, -1, -1), Material::STONE),
+        ];
+
+        let ro = Vec3::new(-0.5, 0.5, -0.5);
+        let hit = raycast(ro, Vec3::new(-1.0, 0.0, 0.0), 10.0, blocks(&list)).unwrap();
+        assert_eq!((hit.pos, hit.normal), (v(-6, 0, -1), v(1, 0, 0)));
+        assert_eq!(hit.t, 4.5);
+
+        let hit = raycast(ro, Vec3::new(0.0, -1.0, 0.0), 10.0, blocks(&list)).unwrap();
+        assert_eq!((hit.pos, hit.normal), (v(-1, -1, -1), v(0, 1, 0)));
+        assert_eq!(hit.t, 0.5);
+    }
+
+    #[test]
+    fn inside() {
+        let list = [(v(2, 3, 4), Material::STONE)];
+        let hit = raycast(
+            Vec3::new(2.2, 3.9, 4.5),
+            Vec3::new(0.0, 1.0, 0.0),
+            10.0,
+            blocks(&list),
+        )
+        .unwrap();
+        assert_eq!((hit.pos, hit.normal), (v(2, 3, 4), IVec3::zeros()));
+        assert_eq!(hit.t, 0.0);
+    }
+
+    #[test]
+    fn skips_water() {
+        let list = [(v(2, 0, 0), Material::WATER), (v(4, 0, 0), Material::STONE)];
+        let hit = raycast(
+            Vec3::new(0.5, 0.5, 0.5),
+            Vec3::new(1.0, 0.0, 0.0),
+            10.0,
+            blocks(&list),
+        )
+        .unwrap();
+        assert_eq!(hit.pos, v(4, 0, 0));
+    }
+
+    #[test]
+    fn max_t() {
+        let list = [(v(5, 0, 0), Material::STONE)];
+        let (ro, rd) = (Vec3::new(0.5, 0.5, 0.5), Vec3::new(1.0, 0.0, 0.0));
+        // It gets to the block 4.5 along the ray
+        assert!(raycast(ro, rd, 4.4, blocks(&list)).is_none());
+        let hit = raycast(ro, rd, 4.6, blocks(&list)).unwrap();
+        assert_eq!((hit.pos, hit.t), (v(5, 0, 0), 4.5));
+    }
+
+    /// Empty chunks from -2 to 1 along x
+    fn world() -> World {
+        let mut world = World::new();
+        for x in -2..=1 {
+            world.add_chunk(v(x, 0, 0), Chunk::empty());
+        }
+        world
+    }
+
+    #[test]
+    fn chunk_borders() {
+        let mut world = world();
+        world.set_block(Vec3::new(40.0, 5.0, 5.0), Material::STONE);
+
+        // Through three chunk borders to the stone in chunk 1
+        let ro = Vec3::new(-50.5, 5.5, 5.5);
+        let hit = world.raycast(ro, Vec3::new(1.0, 0.0, 0.0), 100.0).unwrap();
+        assert_eq!((hit.pos, hit.normal), (v(40, 5, 5), v(-1, 0, 0)));
+        assert_eq!(hit.t, 90.5);
+
+        // Chunk -3 isn't loaded, which stops the ray
+        assert!(world
+            .raycast(ro, Vec3::new(-1.0, 0.0, 0.0), 100.0)
+            .is_none());
+    }
+
+    #[test]
+    fn view_distance() {
+        let mut world = world();
+        let eye = Vec3::new(0.5, 2.6, 0.5);
+        // A floor, with a wall in the way of part of it
+        world.fill_box(v(-20, 0, -10), v(10, 0, 10), Material::STONE);
+        world.fill_box(v(3, 1, -10), v(3, 3, 10), Material::STONE);
+
+        // Straight down to the top of the block under us
+        let d = world.view_distance(eye, v(0, 0, 0)).unwrap();
+        assert!((d - 1.6).abs() < 1e-5, "{}", d);
+        assert!(world.view_distance(eye, v(2, 0, 0)).is_some());
+        // Far away along the floor, where the ray to the middle would go through the blocks in front of it
+        assert!(world.view_distance(eye, v(-15, 0, 0)).is_some());
+        assert_eq!(world.view_distance(eye, v(5, 0, 0)), None);
+        // Empty blocks to put something in
+        let d = world.view_distance(eye, v(2, 1, 0)).unwrap();
+        assert!(d < (Vec3::new(2.5, 1.5, 0.5) - eye).norm());
+        assert_eq!(world.view_distance(eye, v(4, 1, 0)), None);
+    }
+
+    /// Chunks from -1 to 0 along each axis, with stone scattered around
+    fn stony() -> World {
+        let mut world = World::new();
+        for x in -1..=0 {
+            for y in -1..=0 {
+                for z in -1..=0 {
+                    let c = v(x, y, z);
+                    world.add_chunk(
+                        c,
+                        Chunk::full(&|p| {
+                            let p = p.map(|x| x as i64);
+                            if hash(0, &[c.x as i64, c.y as i64, c.z as i64, p.x, p.y, p.z]) < 0.2 {
+                                Material::STONE
+                            } else {
+                                Material::AIR
+                            }
+                        }),
+                    );
+                }
+            }
+        }
+        world
+    }
+
+    /// Blocks that aren't loaded count as air
+    fn get(world: &World, b: IVec3) -> Material {
+        world.block(b.map(|x| x as f32)).unwrap_or(Material::AIR)
+    }
+
+    /// Checks that every block in `world` is what `f` says, given what it was in `before`
+    fn check(world: &World, before: &World, f: impl Fn(IVec3, Material) -> Material) {
+        for x in -32..32 {
+            for y in -32..32 {
+                for z in -32..32 {
+                    let b = v(x, y, z);
+                    assert_eq!(get(world, b), f(b, get(before, b)), "at {:?}", b);
+                }
+            }
+        }
+    }
+
+    fn in_box(b: IVec3, min: IVec3, max: IVec3) -> bool {
+        (0..3).all(|a| b[a] >= min[a] && b[a] <= max[a])
+    }
+
+    #[test]
+    fn fill_box() {
+        let mut world = stony();
+        // It goes past the loaded chunks at -x and +y
+        let (min, max) = (v(-40, -3, -5), v(10, 35, 2));
+        let changed = world.fill_box(min, max, Material::GOLD);
+        check(&world, &stony(), |b, m| {
+            if in_box(b, min, max) {
+                Material::GOLD
+            } else {
+                m
+            }
+        });
+        let mut expected = Vec::new();
+        for x in -1..=0 {
+            for y in -1..=0 {
+                for z in -1..=0 {
+                    expected.push(v(x, y, z));
+                }
+            }
+        }
+        assert_eq!(changed, expected);
+
+        // Nothing changes the second time
+        assert!(world.fill_box(min, max, Material::GOLD).is_empty());
+    }
+
+    #[test]
+    fn fill_sphere() {
+        let mut world = stony();
+        let center = Vec3::new(0.3, 1.0, -2.0);
+        let changed = world.fill_sphere(center, 9.5, Material::DIRT);
+        check(&world, &stony(), |b, m| {
+            if (b.map(|x| x as f32 + 0.5) - center).norm() <= 9.5 {
+                Material::DIRT
+            } else {
+                m
+            }
+        });
+        assert_eq!(changed.len(), 8);
+    }
+
+    #[test]
+    fn replace() {
+        let mut world = stony();
+        let (min, max) = (v(-5, -40, 20), v(40, 6, 33));
+        world.replace(min, max, Material::STONE, Material::COAL);
+        world.replace(min, max, Material::AIR, Material::SAND);
+        check(&world, &stony(), |b, m| match m {
+            Material::STONE if in_box(b, min, max) => Material::COAL,
+            Material::AIR if in_box(b, min, max) => Material::SAND,
+            m => m,
+        });
+    }
+
+    #[test]
+    fn copy_paste() {
+        let mut world = stony();
+        let (min, max) = (v(-10, -40, 3), v(50, 3, 9));
+        let s = world.copy_region(min, max);
+        assert_eq!(s.size(), UVec3::new(61, 44, 7));
+
+        // Across the borders between chunks along every axis
+        let at = v(-30, -20, -3);
+        world.paste_region(at, &s, false);
+        let before = stony();
+        let end = at + s.size().map(|x| x as i32) - v(1, 1, 1);
+        check(&world, &before, |b, m| {
+            if in_box(b, at, end) {
+                // The part that was in unloaded chunks comes out as air
+                get(&before, b - at + min)
+            } else {
+                m
+            }
+        });
+
+        // Copying it back out gets the same thing
+        let again = world.copy_region(at, end);
+        for x in 0..61 {
+            for y in 0..44 {
+                for z in 0..7 {
+                    let b = UVec3::new(x, y, z);
+                    assert_eq!(s.block(b), again.block(b));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn paste_skip_air() {
+        let mut world = stony();
+        let s = world.copy_region(v(-20, -20, -20), v(20, 20, 20));
+        let at = v(-5, -7, 3);
+        world.paste_region(at, &s, true);
+        let before = stony();
+        check(&world, &before, |b, m| {
+            let src = b - at + v(-20, -20, -20);
+            if in_box(src, v(-20, -20, -20), v(20, 20, 20)) && get(&before, src) != Material::AIR {
+                get(&before, src)
+            } else {
+                m
+            }
+        });
+    }
+
+    #[test]
+    fn bad_world_names() {
+        for name in &["", ".", "..", "a/b", "..\\a"] {
+            assert!(world_dir(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn save_meta() {
+        let dir = std::env::temp_dir().join(format!("voxrs-meta-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut meta = WorldMeta::new(12, GenConfig::default(), WorldType::Void, None);
+        meta.save(&dir);
+        meta.time = 40.0;
+        let inventory = vec![(Material::STONE, 3), (Material::GOLD, 1)];
+        meta.inventories
+            .insert("steve".to_string(), inventory.clone());
+        meta.save(&dir);
+
+        // Saving again replaces it, and there's nothing left over from writing it
+        let loaded = WorldMeta::load_or_create(&dir, &GameConfig::default());
+        assert_eq!((loaded.seed, loaded.time), (12, 40.0));
+        assert_eq!(loaded.inventories.get("steve"), Some(&inventory));
+        assert_eq!(loaded.inventories.len(), 1);
+        let files: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|f| f.unwrap().file_name())
+            .collect();
+        assert_eq!(files, ["world.ron"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}